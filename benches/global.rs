@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) 2021-2022 Tobias Heider <me@tobhe.de>
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use red::Editor;
+use regex::Regex;
+use std::env;
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+const LINES: usize = 100_000;
+const RUNS: u32 = 5;
+
+// Runs a substitution for every line of a generated file with 100k lines. The
+// pattern is compiled once and then taken from the cache, compiling it for
+// every line instead is timed for comparison.
+fn main() {
+	let path = env::temp_dir().join(format!("red-bench-global-{}.txt", std::process::id()));
+	let data: String = (0..LINES)
+		.map(|i| format!("{} the quick brown fox jumps over the lazy dog\n", i))
+		.collect();
+	fs::write(&path, &data).expect("cannot write fixture");
+	let path = path.to_str().expect("temp path is not UTF-8").to_string();
+
+	let mut elapsed = Duration::ZERO;
+	for _ in 0..RUNS {
+		let mut editor = Editor::with_output(io::sink());
+		editor.open(&path).expect("cannot open fixture");
+		let start = Instant::now();
+		editor
+			.execute("g/fox/s/dog/cat/")
+			.expect("cannot run global command");
+		elapsed += start.elapsed();
+	}
+	println!(
+		"g/fox/s/dog/cat/ over {} lines: {:?} per run",
+		LINES,
+		elapsed / RUNS
+	);
+
+	let start = Instant::now();
+	for _ in 0..RUNS {
+		compile_each_line();
+	}
+	println!(
+		"compiling the pattern for each of {} lines: {:?} per run",
+		LINES,
+		start.elapsed() / RUNS
+	);
+	let _ = fs::remove_file(&path);
+}
+
+// What g used to pay on top, one compiled pattern per line
+#[allow(clippy::regex_creation_in_loops)]
+fn compile_each_line() {
+	for _ in 0..LINES {
+		Regex::new("dog").expect("invalid regex");
+	}
+}
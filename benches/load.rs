@@ -0,0 +1,47 @@
+/*
+ * Copyright (c) 2021-2022 Tobias Heider <me@tobhe.de>
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use red::Editor;
+use std::env;
+use std::fs;
+use std::io;
+use std::time::Instant;
+
+const LINES: usize = 1_000_000;
+const RUNS: u32 = 5;
+
+// Loads a generated file with a million lines a few times
+fn main() {
+	let path = env::temp_dir().join(format!("red-bench-{}.txt", std::process::id()));
+	let data: String = (0..LINES)
+		.map(|i| format!("{} the quick brown fox jumps over the lazy dog\n", i))
+		.collect();
+	fs::write(&path, &data).expect("cannot write fixture");
+	let path = path.to_str().expect("temp path is not UTF-8").to_string();
+
+	let start = Instant::now();
+	for _ in 0..RUNS {
+		let mut editor = Editor::with_output(io::sink());
+		editor.open(&path).expect("cannot open fixture");
+	}
+	println!(
+		"load {} lines ({} bytes): {:?} per run",
+		LINES,
+		data.len(),
+		start.elapsed() / RUNS
+	);
+	let _ = fs::remove_file(&path);
+}
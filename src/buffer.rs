@@ -14,39 +14,154 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+use crate::lines::{self, Lines};
+use std::cmp;
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
-use std::iter::FromIterator;
+use std::iter::{self, FromIterator};
+use std::mem;
 use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::ops::RangeBounds;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Buffer {
 	pub marks: [Option<usize>; 26],
 	pub changed: bool,
 	pub curline: usize,
+	pub final_newline: bool,
+	pub crlf: bool,
 
-	lines: Vec<String>,
+	lines: Lines,
+	global: Vec<usize>,
+}
+
+// The state of a buffer an undo goes back to
+#[derive(Clone, Debug)]
+pub struct BufferSnapshot {
+	lines: Lines,
+	marks: [Option<usize>; 26],
+	changed: bool,
+	curline: usize,
+	final_newline: bool,
+	size: usize,
+}
+
+impl BufferSnapshot {
+	// Roughly the memory held by the lines
+	#[inline]
+	pub fn size(&self) -> usize {
+		self.size
+	}
 }
 
 impl Buffer {
 	pub const fn new() -> Self {
 		Buffer {
-			lines: Vec::new(),
+			lines: Lines::new(),
+			global: Vec::new(),
 			marks: [None; 26],
 			changed: false,
 			curline: 0,
+			final_newline: true,
+			crlf: false,
 		}
 	}
 
+	// Room for n more lines, so large files load without reallocating
+	pub fn reserve(&mut self, n: usize) {
+		self.lines.reserve(n);
+	}
+
+	pub fn snapshot(&self) -> BufferSnapshot {
+		BufferSnapshot {
+			lines: self.lines.clone(),
+			marks: self.marks,
+			changed: self.changed,
+			curline: self.curline,
+			final_newline: self.final_newline,
+			size: self
+				.lines
+				.iter()
+				.map(|l| l.len() + mem::size_of::<String>())
+				.sum(),
+		}
+	}
+
+	pub fn restore(&mut self, snap: BufferSnapshot) {
+		self.lines = snap.lines;
+		self.marks = snap.marks;
+		self.changed = snap.changed;
+		self.curline = snap.curline;
+		self.final_newline = snap.final_newline;
+	}
+
+	// Starts over with no lines or marks, keeping the allocation
+	pub fn clear(&mut self) {
+		self.lines.clear();
+		self.global.clear();
+		self.marks = [None; 26];
+		self.changed = false;
+		self.curline = 0;
+		self.final_newline = true;
+		self.crlf = false;
+	}
+
+	// A gap buffer makes edits in the middle of huge files cheap
+	pub fn set_large(&mut self, large: bool) {
+		self.lines.set_gap(large);
+	}
+
 	#[inline]
 	pub fn len(&self) -> usize {
 		self.lines.len()
 	}
 
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.lines.len() == 0
+	}
+
+	#[inline]
+	pub fn get(&self, index: usize) -> Option<&String> {
+		self.lines.get(index)
+	}
+
+	// Handing out a line for editing counts as a change
+	pub fn line_mut(&mut self, index: usize) -> Option<&mut String> {
+		let line = self.lines.get_mut(index)?;
+		self.changed = true;
+		Some(line)
+	}
+
+	// Marks stay in place as the number of lines is unchanged
+	pub fn replace_line(&mut self, index: usize, new: String) -> Option<String> {
+		self.line_mut(index).map(|line| mem::replace(line, new))
+	}
+
+	#[inline]
+	pub fn line_ending(&self) -> &'static str {
+		if self.crlf {
+			"\r\n"
+		} else {
+			"\n"
+		}
+	}
+
+	// Size as written to a file, including newlines
+	pub fn byte_len(&self) -> usize {
+		let nl = self.line_ending().len();
+		let len = self.lines.iter().map(|l| l.len() + nl).sum();
+		if self.final_newline {
+			len
+		} else {
+			len.saturating_sub(nl)
+		}
+	}
+
 	#[inline]
 	pub fn push(&mut self, val: String) {
-		self.lines.push(val)
+		let len = self.lines.len();
+		self.lines.splice(len, len, std::iter::once(val))
 	}
 
 	pub fn replace_iter<R, I>(&mut self, range: R, replace_with: I)
@@ -55,23 +170,32 @@ impl Buffer {
 		I: IntoIterator<Item = String>,
 	{
 		let old = self.lines.len() as i64;
-		self.lines.splice(range.clone(), replace_with);
+		let (from, to) = self.bounds(&range);
+		self.lines.splice(cmp::min(from, to), to, replace_with);
 		let diff = (self.lines.len() as i64) - old;
 
+		// Lines appended after the last one end with a newline again
+		let start = match range.start_bound() {
+			Included(start) => *start as i64,
+			Excluded(start) => (*start as i64) + 1,
+			Unbounded => 0,
+		};
+		if start >= old && diff > 0 {
+			self.final_newline = true;
+		}
+
 		// Remove marks in deleted range,
 		for mark in self.marks.iter_mut() {
-			*mark = if let Some(ref index) = mark {
-				if range.contains(index) {
-					None
-				} else if range_after(&range, index) {
-					Some(usize::try_from((*index as i64) + diff).unwrap())
-				} else {
-					Some(*index)
-				}
-			} else {
-				*mark
-			}
+			*mark = mark.and_then(|index| move_index(&range, diff, index));
 		}
+		self.global
+			.retain_mut(|index| match move_index(&range, diff, *index) {
+				Some(i) => {
+					*index = i;
+					true
+				}
+				None => false,
+			});
 
 		// Update line to end of insertion/deletion
 		self.curline = match range.end_bound() {
@@ -85,26 +209,161 @@ impl Buffer {
 		self.changed = true;
 	}
 
+	// Deletes the ascending lines in a single pass instead of one splice each.
+	// Like d the line after the last deleted one becomes current.
+	pub fn delete_lines(&mut self, lines: &[usize]) {
+		let last = match lines.last() {
+			Some(last) => *last,
+			None => return,
+		};
+		let mut index = 0;
+		let mut deleted = lines.iter().peekable();
+		self.lines.retain(|_| {
+			let keep = deleted.next_if_eq(&&index).is_none();
+			index += 1;
+			keep
+		});
+
+		let map = |i: usize| match lines.binary_search(&i) {
+			Ok(_) => None,
+			Err(n) => Some(i - n),
+		};
+		for mark in self.marks.iter_mut() {
+			*mark = mark.and_then(map);
+		}
+		self.global = self.global.iter().filter_map(|i| map(*i)).collect();
+		self.curline = cmp::min(last + 1 - lines.len(), self.lines.len().saturating_sub(1));
+		self.changed = true;
+	}
+
+	// Replaces the lines with a single one joined by sep and returns its index.
+	// Marks on the joined lines move to the result.
+	pub fn join_range<R: RangeBounds<usize>>(&mut self, range: R, sep: &str) -> Option<usize> {
+		let (start, end) = self.bounds(&range);
+		if start >= end {
+			return None;
+		}
+		let line = self
+			.range(start, end - 1)
+			.map(String::as_str)
+			.collect::<Vec<_>>()
+			.join(sep);
+		let joined: Vec<bool> = self
+			.marks
+			.iter()
+			.map(|m| m.is_some_and(|i| i >= start && i < end))
+			.collect();
+		self.replace_iter(start..end, iter::once(line));
+		for (mark, joined) in self.marks.iter_mut().zip(joined) {
+			if joined {
+				*mark = Some(start);
+			}
+		}
+		self.curline = start;
+		Some(start)
+	}
+
+	// Moves src to be inserted at dest, counted before the move. Marks follow
+	// their lines, a dest inside src is refused.
+	pub fn move_lines<R: RangeBounds<usize>>(&mut self, src: R, dest: usize) -> Option<()> {
+		let (start, end) = self.bounds(&src);
+		if start >= end || dest > self.lines.len() || (dest > start && dest < end) {
+			return None;
+		}
+		let len = end - start;
+		let map: Box<dyn Fn(usize) -> usize> = if dest >= end {
+			self.lines.slice_mut(start, dest).rotate_left(len);
+			Box::new(move |i| match i {
+				i if i >= start && i < end => i + (dest - end),
+				i if i >= end && i < dest => i - len,
+				i => i,
+			})
+		} else {
+			self.lines.slice_mut(dest, end).rotate_right(len);
+			Box::new(move |i| match i {
+				i if i >= start && i < end => i - (start - dest),
+				i if i >= dest && i < start => i + len,
+				i => i,
+			})
+		};
+		for mark in self.marks.iter_mut() {
+			*mark = mark.map(&map);
+		}
+		for index in self.global.iter_mut() {
+			*index = map(*index);
+		}
+		self.curline = map(end - 1);
+		self.changed = true;
+		Some(())
+	}
+
+	// Inserts copies of src at dest, 0 prepends. Marks after dest shift down.
+	pub fn copy_lines<R: RangeBounds<usize>>(&mut self, src: R, dest: usize) -> Option<()> {
+		let (start, end) = self.bounds(&src);
+		if start >= end || dest > self.lines.len() {
+			return None;
+		}
+
+		// Copy first, the destination may lie within the range
+		let lines: Vec<_> = self.iter().skip(start).take(end - start).cloned().collect();
+		self.replace_iter(dest..dest, lines);
+		Some(())
+	}
+
+	fn bounds<R: RangeBounds<usize>>(&self, range: &R) -> (usize, usize) {
+		let start = match range.start_bound() {
+			Included(start) => *start,
+			Excluded(start) => start + 1,
+			Unbounded => 0,
+		};
+		let end = match range.end_bound() {
+			Included(end) => end + 1,
+			Excluded(end) => *end,
+			Unbounded => self.lines.len(),
+		};
+		(start, cmp::min(end, self.lines.len()))
+	}
+
+	// Lines are handed out in ascending order
+	pub fn set_global<I: IntoIterator<Item = usize>>(&mut self, lines: I) {
+		self.global = lines.into_iter().collect();
+		self.global.reverse();
+	}
+
+	#[inline]
+	pub fn next_global(&mut self) -> Option<usize> {
+		self.global.pop()
+	}
+
 	#[inline]
-	pub fn iter(&self) -> std::slice::Iter<String> {
+	pub fn iter(&self) -> lines::Iter<'_> {
 		self.lines.iter()
 	}
+
+	// Lines from to to inclusive, clamped to the end of the buffer. An inverted
+	// range is empty.
+	pub fn range(&self, from: usize, to: usize) -> lines::Iter<'_> {
+		let to = cmp::min(to.saturating_add(1), self.len());
+		self.lines.range(cmp::min(from, to), to)
+	}
 }
 
 impl Extend<String> for Buffer {
 	#[inline]
 	fn extend<I: IntoIterator<Item = String>>(&mut self, iter: I) {
-		self.lines.extend(iter)
+		let len = self.lines.len();
+		self.lines.splice(len, len, iter)
 	}
 }
 
 impl fmt::Display for Buffer {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(
-			f,
-			"{}",
-			self.lines.iter().fold(String::new(), |e, l| e + l + "\n")
-		)
+		let nl = self.line_ending();
+		let mut s = self.lines.iter().fold(String::new(), |e, l| e + l + nl);
+		if !self.final_newline {
+			s.truncate(s.len().saturating_sub(nl.len()));
+		}
+		write!(f, "{}", s)
 	}
 }
 
@@ -114,28 +373,44 @@ impl IntoIterator for Buffer {
 
 	#[inline]
 	fn into_iter(self) -> std::vec::IntoIter<String> {
-		self.lines.into_iter()
+		self.lines.into_vec().into_iter()
 	}
 }
 
 impl FromIterator<String> for Buffer {
 	#[inline]
 	fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Buffer {
-		let lines = Vec::<String>::from_iter(iter.into_iter());
-		let last = if lines.len() > 0 {
+		let lines = Vec::<String>::from_iter(iter);
+		let last = if !lines.is_empty() {
 			lines.len() - 1
 		} else {
 			lines.len()
 		};
 		Buffer {
-			lines: lines,
+			lines: Lines::from(lines),
+			global: Vec::new(),
 			marks: [None; 26],
 			changed: false,
 			curline: last,
+			final_newline: true,
+			crlf: false,
 		}
 	}
 }
 
+fn move_index<R>(range: &R, diff: i64, index: usize) -> Option<usize>
+where
+	R: RangeBounds<usize>,
+{
+	if range.contains(&index) {
+		None
+	} else if range_after(range, &index) {
+		Some(usize::try_from((index as i64) + diff).unwrap())
+	} else {
+		Some(index)
+	}
+}
+
 fn range_after<R>(range: &R, item: &usize) -> bool
 where
 	R: RangeBounds<usize>,
@@ -146,3 +421,110 @@ where
 		Unbounded => false,
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Lines 1 to n, mark a on the first line, b on the second and so on
+	fn numbered(n: usize) -> Buffer {
+		let mut buf: Buffer = (1..=n).map(|i| i.to_string()).collect();
+		for i in 0..n {
+			buf.marks[i] = Some(i);
+		}
+		buf
+	}
+
+	fn lines(buf: &Buffer) -> Vec<&str> {
+		buf.iter().map(String::as_str).collect()
+	}
+
+	// Every mark still points at the line it was set on
+	fn assert_marks_follow(buf: &Buffer, n: usize) {
+		for i in 0..n {
+			let line = buf.marks[i].and_then(|m| buf.get(m));
+			assert_eq!(line, Some(&(i + 1).to_string()));
+		}
+	}
+
+	#[test]
+	fn move_lines_down() {
+		let mut buf = numbered(6);
+		assert_eq!(buf.move_lines(1..=2, 5), Some(()));
+		assert_eq!(lines(&buf), ["1", "4", "5", "2", "3", "6"]);
+		assert_eq!(buf.marks[1], Some(3));
+		assert_eq!(buf.marks[3], Some(1));
+		assert_marks_follow(&buf, 6);
+		assert_eq!(buf.curline, 4);
+	}
+
+	#[test]
+	fn move_lines_up() {
+		let mut buf = numbered(6);
+		assert_eq!(buf.move_lines(4..=5, 1), Some(()));
+		assert_eq!(lines(&buf), ["1", "5", "6", "2", "3", "4"]);
+		assert_eq!(buf.marks[4], Some(1));
+		assert_eq!(buf.marks[1], Some(3));
+		assert_marks_follow(&buf, 6);
+		assert_eq!(buf.curline, 2);
+	}
+
+	#[test]
+	fn move_lines_into_itself() {
+		let mut buf = numbered(6);
+		assert_eq!(buf.move_lines(1..=3, 2), None);
+		assert_eq!(lines(&buf), ["1", "2", "3", "4", "5", "6"]);
+	}
+
+	#[test]
+	fn copy_lines_before_source() {
+		let mut buf = numbered(6);
+		assert_eq!(buf.copy_lines(3..=4, 1), Some(()));
+		assert_eq!(lines(&buf), ["1", "4", "5", "2", "3", "4", "5", "6"]);
+		assert_eq!(buf.marks[0], Some(0));
+		assert_eq!(buf.marks[3], Some(5));
+		assert_marks_follow(&buf, 6);
+	}
+
+	#[test]
+	fn copy_lines_after_source() {
+		let mut buf = numbered(6);
+		assert_eq!(buf.copy_lines(0..=1, 4), Some(()));
+		assert_eq!(lines(&buf), ["1", "2", "3", "4", "1", "2", "5", "6"]);
+		assert_eq!(buf.marks[0], Some(0));
+		assert_eq!(buf.marks[4], Some(6));
+		assert_marks_follow(&buf, 6);
+	}
+
+	#[test]
+	fn restore_snapshot() {
+		let mut buf = numbered(6);
+		let snap = buf.snapshot();
+		buf.replace_iter(0..3, iter::empty::<String>());
+		buf.marks[25] = Some(0);
+		buf.restore(snap);
+		assert_eq!(lines(&buf), ["1", "2", "3", "4", "5", "6"]);
+		assert_eq!(buf.marks[25], None);
+		assert_marks_follow(&buf, 6);
+		assert!(!buf.changed);
+	}
+
+	#[test]
+	fn join_range_collapses_marks() {
+		let mut buf = numbered(6);
+		assert_eq!(buf.join_range(1..=3, ","), Some(1));
+		assert_eq!(lines(&buf), ["1", "2,3,4", "5", "6"]);
+		assert_eq!(
+			buf.marks[..6],
+			[Some(0), Some(1), Some(1), Some(1), Some(2), Some(3)]
+		);
+		assert_eq!(buf.curline, 1);
+	}
+
+	#[test]
+	fn join_range_empty() {
+		let mut buf = numbered(3);
+		assert_eq!(buf.join_range(1..1, ""), None);
+		assert_eq!(lines(&buf), ["1", "2", "3"]);
+	}
+}
@@ -14,37 +14,137 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+use std::borrow::Cow;
 use std::error::Error;
 use std::fmt;
 use std::num::TryFromIntError;
 
-#[derive(Debug)]
-pub struct CommandError {
-	details: String,
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandError {
+	CommandFailed,
+	DryRun,
+	ExpectedSingleLine,
+	Interrupted,
+	InvalidAddress,
+	InvalidCommand,
+	InvalidCount,
+	InvalidDestination,
+	InvalidMark,
+	InvalidPath,
+	InvalidRegex(String),
+	ModifiedBuffer,
+	NestedGlobal,
+	NoFilename,
+	NoMatch,
+	NoOtherBuffer,
+	NoPreviousCommand,
+	NoPreviousSearch,
+	NoPreviousSubstitution,
+	NothingToRedo,
+	NothingToUndo,
+	Restricted,
+	UnknownCommand(String),
+	Other(String),
 }
 
 impl CommandError {
-	pub fn new(msg: &str) -> CommandError {
-		CommandError {
-			details: msg.to_string(),
+	fn as_str(&self) -> &str {
+		match self {
+			CommandError::CommandFailed => "Command failed",
+			CommandError::DryRun => "dry run",
+			CommandError::ExpectedSingleLine => "Expected single line",
+			CommandError::Interrupted => "interrupted",
+			CommandError::InvalidAddress => "invalid address",
+			CommandError::InvalidCommand => "invalid command",
+			CommandError::InvalidCount => "invalid count",
+			CommandError::InvalidDestination => "invalid destination",
+			CommandError::InvalidMark => "invalid mark",
+			CommandError::InvalidPath => "invalid path",
+			CommandError::InvalidRegex(_) => "invalid regex",
+			CommandError::ModifiedBuffer => "warning: file modified",
+			CommandError::NestedGlobal => "cannot nest global commands",
+			CommandError::NoFilename => "no current filename",
+			CommandError::NoMatch => "no match",
+			CommandError::NoOtherBuffer => "no other buffer",
+			CommandError::NoPreviousCommand => "no previous command",
+			CommandError::NoPreviousSearch => "no previous search",
+			CommandError::NoPreviousSubstitution => "no previous substitution",
+			CommandError::NothingToRedo => "nothing to redo",
+			CommandError::NothingToUndo => "nothing to undo",
+			CommandError::Restricted => "restricted mode",
+			CommandError::UnknownCommand(_) => "unknown command",
+			CommandError::Other(s) => s,
+		}
+	}
+
+	// Full sentences for H and h
+	pub fn explain(&self) -> Cow<'_, str> {
+		match self {
+			CommandError::UnknownCommand(c) => format!("Unknown command: {}", c).into(),
+			e => e.sentence().into(),
+		}
+	}
+
+	fn sentence(&self) -> &str {
+		match self {
+			CommandError::CommandFailed => "Shell command could not be run",
+			CommandError::DryRun => "Only substitutions can be previewed in a dry run",
+			CommandError::ExpectedSingleLine => "Command takes a single address, not a range",
+			CommandError::Interrupted => "Command was interrupted",
+			CommandError::InvalidAddress => "Address does not refer to a line in the buffer",
+			CommandError::InvalidCommand => "Command is incomplete or has invalid arguments",
+			CommandError::InvalidCount => "Count must be at least 1",
+			CommandError::InvalidDestination => "Destination address falls within the moved range",
+			CommandError::InvalidMark => "Mark is not set",
+			CommandError::InvalidPath => "Cannot open file",
+			CommandError::InvalidRegex(s) => s,
+			CommandError::ModifiedBuffer => {
+				"Buffer modified since last write, repeat the command to discard changes"
+			}
+			CommandError::NestedGlobal => "Global commands cannot be nested",
+			CommandError::NoFilename => "No current filename",
+			CommandError::NoMatch => "No line matches the regular expression",
+			CommandError::NoOtherBuffer => "No other file is being edited",
+			CommandError::NoPreviousCommand => "No previous command to repeat",
+			CommandError::NoPreviousSearch => "No previous regular expression",
+			CommandError::NoPreviousSubstitution => "No substitution has been performed yet",
+			CommandError::NothingToRedo => "No undone change to redo",
+			CommandError::NothingToUndo => "Nothing to undo",
+			CommandError::Restricted => {
+				"Shell commands and paths with / are not allowed in restricted mode"
+			}
+			CommandError::UnknownCommand(_) => "Unknown command",
+			CommandError::Other(s) => s,
 		}
 	}
 }
 
 impl fmt::Display for CommandError {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "{}", self.details)
+		match self {
+			CommandError::UnknownCommand(c) => write!(f, "unknown command: {}", c),
+			e => write!(f, "{}", e.as_str()),
+		}
 	}
 }
 
 impl Error for CommandError {
 	fn description(&self) -> &str {
-		&self.details
+		self.as_str()
+	}
+}
+
+// Only the last line of a syntax error says what is wrong, the others repeat the pattern
+impl From<regex::Error> for CommandError {
+	fn from(err: regex::Error) -> Self {
+		let msg = err.to_string();
+		let line = msg.lines().last().unwrap_or_default();
+		CommandError::InvalidRegex(String::from(line.strip_prefix("error: ").unwrap_or(line)))
 	}
 }
 
 impl From<TryFromIntError> for CommandError {
 	fn from(err: TryFromIntError) -> Self {
-		CommandError::new(&err.to_string())
+		CommandError::Other(err.to_string())
 	}
 }
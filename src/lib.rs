@@ -0,0 +1,1647 @@
+/*
+ * Copyright (c) 2021-2022 Tobias Heider <me@tobhe.de>
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+mod buffer;
+mod error;
+mod lines;
+mod parser;
+mod term;
+
+use crate::buffer::{Buffer, BufferSnapshot};
+use crate::parser::{
+	command_text, is_command, parse_command, parse_terminator, print_flag_set, Address,
+	AddressRange, Command, PrintFlag,
+};
+use std::cmp;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::iter::{self, FromIterator};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use regex::Regex;
+
+pub use crate::error::CommandError;
+
+pub type Result<T> = std::result::Result<T, CommandError>;
+
+const DEFAULT_PROMPT: &str = "* ";
+const DEFAULT_WINDOW: usize = 22;
+const LIST_WIDTH: usize = 72;
+const REGEX_CACHE: usize = 8;
+const UNDO_BYTES: usize = 64 << 20;
+const UNDO_DEPTH: usize = 100;
+
+// All printed text goes to the output of the state
+macro_rules! output {
+	($s:expr, $($arg:tt)*) => {
+		let _ = write!($s.out, $($arg)*);
+	};
+}
+
+macro_rules! outputln {
+	($s:expr, $($arg:tt)*) => {
+		let _ = writeln!($s.out, $($arg)*);
+	};
+}
+
+struct State<'a> {
+	backup: bool,
+	buffer: Buffer,
+	commands: Option<Box<dyn BufRead + 'a>>,
+	dry_run: bool,
+	error_occurred: bool,
+	file: String,
+	global: bool,
+	input: Option<Box<dyn BufRead + 'a>>,
+	interrupt: Arc<AtomicBool>,
+	large: bool,
+	last_command: Option<String>,
+	last_error: Option<CommandError>,
+	last_regex: Option<Regex>,
+	last_subst: Option<(Regex, String, usize, bool)>,
+	out: Box<dyn Write + 'a>,
+	prompt: bool,
+	prompt_str: String,
+	quit: bool,
+	redo: Vec<BufferSnapshot>,
+	regex_cache: Vec<(String, Regex)>,
+	restricted: bool,
+	ring: VecDeque<(String, Buffer)>,
+	silent: bool,
+	undo: VecDeque<BufferSnapshot>,
+	undo_depth: usize,
+	verbose: bool,
+	warned: bool,
+	window: usize,
+}
+
+impl Default for State<'_> {
+	fn default() -> Self {
+		State {
+			backup: false,
+			buffer: Buffer::new(),
+			commands: None,
+			dry_run: false,
+			error_occurred: false,
+			file: String::from(""),
+			global: false,
+			input: None,
+			interrupt: Arc::new(AtomicBool::new(false)),
+			large: false,
+			last_command: None,
+			last_error: None,
+			last_regex: None,
+			last_subst: None,
+			out: Box::new(io::stdout().lock()),
+			prompt: false,
+			prompt_str: String::from(DEFAULT_PROMPT),
+			quit: false,
+			redo: Vec::new(),
+			regex_cache: Vec::new(),
+			restricted: false,
+			ring: VecDeque::new(),
+			silent: false,
+			undo: VecDeque::new(),
+			undo_depth: UNDO_DEPTH,
+			verbose: false,
+			warned: false,
+			window: DEFAULT_WINDOW,
+		}
+	}
+}
+
+fn read_to_buffer(s: &mut State, f: &str) -> Result<(Buffer, usize)> {
+	let mut buf = Buffer::new();
+	let len = fill_buffer(s, f, &mut buf)?;
+	Ok((buf, len))
+}
+
+// The filename - reads from stdin until EOF, buf is only cleared once f has
+// been read. Returns the number of bytes read.
+fn fill_buffer(s: &mut State, f: &str, buf: &mut Buffer) -> Result<usize> {
+	let data = if f == "-" {
+		let mut data = Vec::new();
+		io::stdin()
+			.read_to_end(&mut data)
+			.map_err(|_| CommandError::InvalidPath)?;
+		data
+	} else {
+		fs::read(f).map_err(|_| CommandError::InvalidPath)?
+	};
+	// Invalid bytes are replaced, saving the buffer would not restore them
+	let len = data.len();
+	let text = match String::from_utf8(data) {
+		Ok(text) => text,
+		Err(e) => {
+			if !s.silent {
+				outputln!(s, "warning: {}: invalid UTF-8 replaced", f);
+			}
+			String::from_utf8_lossy(e.as_bytes()).into_owned()
+		}
+	};
+
+	// Files mostly ending lines with \r\n are written back that way
+	let nl = text.bytes().filter(|b| *b == b'\n').count();
+	let cr = text.matches("\r\n").count();
+	let crlf = cr > 0 && cr * 2 >= nl;
+
+	// Remember a missing newline at the end of file
+	buf.clear();
+	buf.reserve(nl + 1);
+	if !text.is_empty() {
+		let body = text.strip_suffix('\n');
+		buf.final_newline = body.is_some();
+		buf.extend(body.unwrap_or(&text).split('\n').map(|l| {
+			String::from(if crlf {
+				l.strip_suffix('\r').unwrap_or(l)
+			} else {
+				l
+			})
+		}));
+		buf.curline = buf.len() - 1;
+	}
+	buf.crlf = crlf;
+	Ok(len)
+}
+
+// An unescaped % in a shell command stands for the current filename
+fn expand_command(s: &State, c: &str) -> Result<String> {
+	let mut res = String::new();
+	let mut chars = c.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' if chars.peek() == Some(&'%') => res.extend(chars.next()),
+			'%' if s.file.is_empty() => return Err(CommandError::NoFilename),
+			'%' => res.push_str(&s.file),
+			c => res.push(c),
+		}
+	}
+	Ok(res)
+}
+
+// The command's stderr goes to the terminal, its exit status is ignored
+fn command_to_buffer(c: &str) -> Result<Buffer> {
+	let out = process::Command::new("sh")
+		.arg("-c")
+		.arg(c)
+		.stderr(Stdio::inherit())
+		.output()
+		.map_err(|_| CommandError::CommandFailed)?;
+	Ok(Buffer::from_iter(
+		String::from_utf8_lossy(&out.stdout)
+			.lines()
+			.map(String::from),
+	))
+}
+
+fn read_file(s: &mut State, f: &str) -> Result<()> {
+	check_path(s, f)?;
+
+	// The lines of the old buffer make room for the new ones
+	let mut buf = mem::replace(&mut s.buffer, Buffer::new());
+	let len = match fill_buffer(s, f, &mut buf) {
+		Ok(len) => len,
+		Err(e) => {
+			s.buffer = buf;
+			return Err(e);
+		}
+	};
+	// Writing back to stdin makes no sense, leave the filename unset
+	edit_state(s, if f == "-" { "" } else { f }, buf);
+	print_count(s, len);
+	Ok(())
+}
+
+// Restricted mode keeps to files in the current directory
+fn check_path(s: &State, f: &str) -> Result<()> {
+	if s.restricted && (f.starts_with('!') || f.contains('/')) {
+		return Err(CommandError::Restricted);
+	}
+	Ok(())
+}
+
+fn check_restricted(s: &State, c: &Option<Command>) -> Result<()> {
+	match c {
+		Some(Command::Exec(_)) if s.restricted => Err(CommandError::Restricted),
+		Some(Command::AppendWrite(Some(f)))
+		| Some(Command::Edit(Some(f)))
+		| Some(Command::File(Some(f)))
+		| Some(Command::Read(Some(f)))
+		| Some(Command::Write(Some(f)))
+		| Some(Command::WriteQuit(Some(f))) => check_path(s, f),
+		_ => Ok(()),
+	}
+}
+
+// Start over editing buf, only the settings are kept
+fn edit_state(s: &mut State, f: &str, mut buf: Buffer) {
+	buf.set_large(s.large);
+	*s = State {
+		backup: s.backup,
+		commands: s.commands.take(),
+		dry_run: s.dry_run,
+		error_occurred: s.error_occurred,
+		input: s.input.take(),
+		interrupt: Arc::clone(&s.interrupt),
+		file: String::from(f),
+		large: s.large,
+		last_command: s.last_command.take(),
+		buffer: buf,
+		out: mem::replace(&mut s.out, Box::new(io::sink())),
+		prompt: s.prompt,
+		prompt_str: mem::take(&mut s.prompt_str),
+		regex_cache: mem::take(&mut s.regex_cache),
+		restricted: s.restricted,
+		ring: mem::take(&mut s.ring),
+		silent: s.silent,
+		undo_depth: s.undo_depth,
+		verbose: s.verbose,
+		window: s.window,
+		..State::default()
+	};
+}
+
+// Byte counts are informational and suppressed in silent mode
+fn print_count(s: &mut State, len: usize) {
+	if !s.silent {
+		outputln!(s, "{}", len);
+	}
+}
+
+fn write_file(s: &State, f: &str, from: usize, to: usize) -> Result<usize> {
+	if f.is_empty() {
+		return Err(CommandError::NoFilename);
+	}
+	// Writing through a symlink replaces its target, not the link
+	let path = fs::canonicalize(f).unwrap_or_else(|_| PathBuf::from(f));
+	let tmp = temp_path(&path).ok_or(CommandError::InvalidPath)?;
+	let mut opts = OpenOptions::new();
+	opts.write(true).create_new(true);
+	if let Ok(meta) = fs::metadata(&path) {
+		// The rename would replace files we could not write to in place
+		OpenOptions::new()
+			.write(true)
+			.open(&path)
+			.or(Err(CommandError::InvalidPath))?;
+		copy_mode(&mut opts, &meta);
+	}
+	let file = match opts.open(&tmp) {
+		Ok(file) => file,
+		// Without write access to the directory the file is overwritten in place
+		Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+			return write_in_place(s, &path, from, to).map_err(|_| CommandError::InvalidPath)
+		}
+		Err(_) => return Err(CommandError::InvalidPath),
+	};
+	write_atomic(s, &path, &tmp, file, from, to).map_err(|_| {
+		let _ = fs::remove_file(&tmp);
+		CommandError::InvalidPath
+	})
+}
+
+// A hidden file next to f, so the final rename stays on one filesystem
+fn temp_path(path: &Path) -> Option<PathBuf> {
+	let name = path.file_name()?.to_str()?;
+	Some(path.with_file_name(format!(".{}.{}.tmp", name, process::id())))
+}
+
+// Readers of f see either the old or the new contents, never a partial write
+fn write_atomic(
+	s: &State,
+	f: &Path,
+	tmp: &Path,
+	file: fs::File,
+	from: usize,
+	to: usize,
+) -> io::Result<usize> {
+	let mut out = BufWriter::new(file);
+	let len = write_range(s, from, to, &mut out)?;
+	let file = out.into_inner().map_err(|e| e.into_error())?;
+	file.sync_all()?;
+	if let Ok(meta) = fs::metadata(f) {
+		// The owner first, changing it may clear setuid bits
+		copy_owner(&meta, tmp);
+		fs::set_permissions(tmp, meta.permissions())?;
+
+		// Keep the previous contents around as f~, f itself never goes missing
+		if s.backup {
+			let bak = backup_path(f);
+			let _ = fs::remove_file(&bak);
+			fs::hard_link(f, &bak).or_else(|_| fs::copy(f, &bak).map(|_| ()))?;
+		}
+	}
+	fs::rename(tmp, f)?;
+	Ok(len)
+}
+
+// Keeps the owner, mode and links of f, but a crash leaves it partially written
+fn write_in_place(s: &State, f: &Path, from: usize, to: usize) -> io::Result<usize> {
+	if s.backup && f.exists() {
+		fs::copy(f, backup_path(f))?;
+	}
+	let file = OpenOptions::new().write(true).truncate(true).open(f)?;
+	let mut out = BufWriter::new(file);
+	let len = write_range(s, from, to, &mut out)?;
+	out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+	Ok(len)
+}
+
+fn backup_path(f: &Path) -> PathBuf {
+	let mut bak = f.as_os_str().to_owned();
+	bak.push("~");
+	PathBuf::from(bak)
+}
+
+// Only root may give files away, a user can still keep the group
+#[cfg(unix)]
+fn copy_owner(meta: &fs::Metadata, path: &Path) {
+	use std::os::unix::fs::{chown, MetadataExt};
+	let _ = chown(path, Some(meta.uid()), Some(meta.gid()))
+		.or_else(|_| chown(path, None, Some(meta.gid())));
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_: &fs::Metadata, _: &Path) {}
+
+// The new contents are never more readable than the old ones, not even while
+// they are written
+#[cfg(unix)]
+fn copy_mode(opts: &mut OpenOptions, meta: &fs::Metadata) {
+	use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+	opts.mode(meta.permissions().mode() & 0o777);
+}
+
+#[cfg(not(unix))]
+fn copy_mode(_: &mut OpenOptions, _: &fs::Metadata) {}
+
+fn append_file(s: &State, f: &str, from: usize, to: usize) -> Result<usize> {
+	if f.is_empty() {
+		return Err(CommandError::NoFilename);
+	}
+	OpenOptions::new()
+		.append(true)
+		.create(true)
+		.open(f)
+		.and_then(|file| write_range(s, from, to, BufWriter::new(file)))
+		.map_err(|_| CommandError::InvalidPath)
+}
+
+fn write_command(s: &State, c: &str, from: usize, to: usize) -> Result<usize> {
+	let mut child = process::Command::new("sh")
+		.arg("-c")
+		.arg(c)
+		.stdin(Stdio::piped())
+		.spawn()
+		.map_err(|_| CommandError::CommandFailed)?;
+
+	// The command may exit without reading everything, dropping stdin closes the pipe
+	// and the size of the range is reported all the same
+	let len = child
+		.stdin
+		.take()
+		.and_then(|stdin| write_range(s, from, to, BufWriter::new(stdin)).ok());
+	child.wait().map_err(|_| CommandError::CommandFailed)?;
+	Ok(len.unwrap_or_else(|| write_range(s, from, to, io::sink()).unwrap_or(0)))
+}
+
+// Streams the lines from..to into out, returning the number of bytes written
+fn write_range<W: Write>(s: &State, from: usize, to: usize, mut out: W) -> io::Result<usize> {
+	let nl = s.buffer.line_ending();
+	let last = s.buffer.len().saturating_sub(1);
+	let mut len = 0;
+	for (i, l) in s.buffer.range(from, to).enumerate() {
+		out.write_all(l.as_bytes())?;
+		len += l.len();
+		if from + i < last || s.buffer.final_newline {
+			out.write_all(nl.as_bytes())?;
+			len += nl.len();
+		}
+	}
+	out.flush()?;
+	Ok(len)
+}
+
+// The last inserted line becomes the current line
+fn buffer_insert(s: &mut State, line: usize, buf: Buffer) {
+	if !buf.is_empty() {
+		s.buffer.replace_iter(line..line, buf);
+	}
+}
+
+fn line_to_index(s: &mut State, l: Address) -> Result<usize> {
+	match l {
+		Address::Abs(c) => offset_line(0, c),
+		// Nothing follows the last line
+		Address::Last(c) => offset_line(s.buffer.len(), c)?
+			.checked_sub(1)
+			.ok_or(CommandError::InvalidAddress),
+		Address::Rel(c) => offset_line(s.buffer.curline, c),
+		Address::Mark(m, c) => {
+			let line = s.buffer.marks[usize::from(m)].ok_or(CommandError::InvalidMark)?;
+			offset_line(line, c)
+		}
+		Address::Next(re, c) => offset_line(find_regex(s, re.as_ref(), true)?, c),
+		Address::Prev(re, c) => offset_line(find_regex(s, re.as_ref(), false)?, c),
+		Address::Zero => Err(CommandError::InvalidAddress),
+	}
+}
+
+// Offsets before the first line are invalid addresses
+fn offset_line(line: usize, c: i32) -> Result<usize> {
+	let line = i64::try_from(line)? + i64::from(c);
+	usize::try_from(line).map_err(|_| CommandError::InvalidAddress)
+}
+
+// Destinations name the line to insert after, 0 inserts at the top
+fn dest_to_index(s: &mut State, l: Address) -> Result<usize> {
+	match l {
+		Address::Zero => Ok(0),
+		_ => {
+			let line = line_to_index(s, l)?;
+			Ok(is_valid(s, line)? + 1)
+		}
+	}
+}
+
+// Escape non-printable characters, fold long lines and mark the line end
+fn list_line(l: &str) -> String {
+	let mut out = String::new();
+	let mut col = 0;
+	for c in l.chars() {
+		let esc = match c {
+			'\\' => String::from("\\\\"),
+			'$' => String::from("\\$"),
+			'\x07' => String::from("\\a"),
+			'\x08' => String::from("\\b"),
+			'\x0c' => String::from("\\f"),
+			'\r' => String::from("\\r"),
+			'\t' => String::from("\\t"),
+			'\x0b' => String::from("\\v"),
+			c if c.is_control() => c
+				.to_string()
+				.bytes()
+				.map(|b| format!("\\{:03o}", b))
+				.collect(),
+			c => c.to_string(),
+		};
+		let width = esc.chars().count();
+		if col + width >= LIST_WIDTH {
+			out.push_str("\\\n");
+			col = 0;
+		}
+		col += width;
+		out.push_str(&esc);
+	}
+	out.push('$');
+	out
+}
+
+// Lines past the end of the buffer are not printed
+fn print_range(s: &mut State, from: usize, to: usize, flags: PrintFlag) {
+	if s.buffer.is_empty() {
+		return;
+	}
+	let to = cmp::min(to, s.buffer.len() - 1);
+	let fun: fn((usize, &String)) -> String = match flags {
+		PrintFlag::Number => |(i, s)| format!("{}\t{}\n", i + 1, s),
+		PrintFlag::List => |(_, s)| format!("{}\n", list_line(s)),
+		_ => |(_, s)| format!("{}\n", s),
+	};
+	let out: String = s
+		.buffer
+		.range(from, to)
+		.enumerate()
+		.map(|(i, l)| fun((from + i, l)))
+		.collect();
+	output!(s, "{}", out);
+}
+
+// Translates ed's \1 to \9 and & into the regex crate's ${1} syntax
+fn replacement_template(new: &str) -> String {
+	let mut res = String::new();
+	let mut chars = new.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'&' => res.push_str("${0}"),
+			'$' => res.push_str("$$"),
+			'\\' => match chars.next() {
+				Some(d @ '1'..='9') => res.push_str(&format!("${{{}}}", d)),
+				Some('$') => res.push_str("$$"),
+				Some(c) => res.push(c),
+				None => res.push('\\'),
+			},
+			c => res.push(c),
+		}
+	}
+	res
+}
+
+// Replaces the nth match, with global all matches from the nth on
+fn substitute(r: &Regex, line: &str, new: &str, n: usize, global: bool) -> Option<String> {
+	let mut matches = r.captures_iter(line).skip(n - 1).peekable();
+	matches.peek()?;
+	let mut res = String::new();
+	let mut end = 0;
+	for caps in matches.take(if global { usize::MAX } else { 1 }) {
+		let m = caps.get(0).unwrap();
+		res.push_str(&line[end..m.start()]);
+		caps.expand(new, &mut res);
+		end = m.end();
+	}
+	res.push_str(&line[end..]);
+	Some(res)
+}
+
+// A new change can no longer be redone. The oldest snapshots are dropped once
+// there are too many or they take too much memory, the last one is always kept.
+fn save_undo(s: &mut State, snap: BufferSnapshot) {
+	s.redo.clear();
+	s.undo.push_back(snap);
+	let mut size: usize = s.undo.iter().map(BufferSnapshot::size).sum();
+	while s.undo.len() > 1 && (s.undo.len() > s.undo_depth || size > UNDO_BYTES) {
+		if let Some(snap) = s.undo.pop_front() {
+			size -= snap.size();
+		}
+	}
+}
+
+// Prints each line s would change as a -old/+new pair, the buffer stays as is
+fn preview_subst(
+	s: &mut State,
+	from: usize,
+	to: usize,
+	r: &Regex,
+	new: &str,
+	n: usize,
+	global: bool,
+) -> Result<()> {
+	let mut out = String::new();
+	for l in s.buffer.range(from, to) {
+		if let Some(line) = substitute(r, l, new, n, global) {
+			out.push_str(&format!("-{}\n", l));
+			for l in line.split('\n') {
+				out.push_str(&format!("+{}\n", l));
+			}
+		}
+	}
+	if out.is_empty() {
+		return Err(CommandError::NoMatch);
+	}
+	output!(s, "{}", out);
+	Ok(())
+}
+
+fn get_regex(s: &mut State, regex: Option<&String>) -> Result<Regex> {
+	if let Some(re) = regex {
+		s.last_regex = Some(compile_regex(s, re)?);
+	}
+	s.last_regex.clone().ok_or(CommandError::NoPreviousSearch)
+}
+
+// Commands run by g for every line would compile the same patterns again,
+// the most recently used ones are kept at the front
+fn compile_regex(s: &mut State, re: &str) -> Result<Regex> {
+	let r = match s.regex_cache.iter().position(|(p, _)| p == re) {
+		Some(i) => s.regex_cache.remove(i).1,
+		None => Regex::new(re)?,
+	};
+	s.regex_cache.insert(0, (String::from(re), r.clone()));
+	s.regex_cache.truncate(REGEX_CACHE);
+	Ok(r)
+}
+
+// An empty pattern searches for the last regex again, starting at the current line
+fn find_regex(s: &mut State, regex: Option<&String>, forward: bool) -> Result<usize> {
+	let r = get_regex(s, regex)?;
+	search_lines(&s.buffer, &r, s.buffer.curline, forward).ok_or(CommandError::NoMatch)
+}
+
+// Forward scans i+1..len and wraps to 0..=i, backward scans i-1 down to 0 and
+// wraps from len-1 down to i. Either way the start line is tried last.
+fn search_lines(buf: &Buffer, r: &Regex, i: usize, forward: bool) -> Option<usize> {
+	let lines = |from: usize, to: usize| {
+		buf.range(from, to)
+			.enumerate()
+			.map(move |(n, l)| (from + n, l))
+	};
+	let len = buf.len();
+	let found = if forward {
+		lines(i + 1, len)
+			.chain(lines(0, i))
+			.find(|(_, l)| r.is_match(l))
+	} else {
+		lines(0, i)
+			.rev()
+			.skip(1)
+			.chain(lines(i, len).rev())
+			.find(|(_, l)| r.is_match(l))
+	};
+	found.map(|(n, _)| n)
+}
+
+fn is_line(from: usize, to: usize) -> Result<usize> {
+	if from != to {
+		return Err(CommandError::ExpectedSingleLine);
+	}
+	Ok(to)
+}
+
+fn is_valid(s: &State, i: usize) -> Result<usize> {
+	if i < s.buffer.len() {
+		Ok(i)
+	} else {
+		Err(CommandError::InvalidAddress)
+	}
+}
+
+// Text and continued commands come from stdin unless other input was set up
+fn read_input(s: &mut State, buf: &mut String) -> io::Result<usize> {
+	match s.input {
+		Some(ref mut r) => read_line(r.as_mut(), buf, &s.interrupt),
+		None => read_line(&mut io::stdin().lock(), buf, &s.interrupt),
+	}
+}
+
+// BufRead::read_line retries interrupted reads, this one gives up once the
+// interrupt flag is set
+fn read_line(r: &mut dyn BufRead, buf: &mut String, interrupt: &AtomicBool) -> io::Result<usize> {
+	let mut line = Vec::new();
+	loop {
+		let chunk = match r.fill_buf() {
+			Ok(chunk) => chunk,
+			Err(e)
+				if e.kind() == io::ErrorKind::Interrupted && !interrupt.load(Ordering::Relaxed) =>
+			{
+				continue
+			}
+			Err(e) => return Err(e),
+		};
+		let (n, done) = match chunk.iter().position(|&b| b == b'\n') {
+			Some(i) => (i + 1, true),
+			None => (chunk.len(), chunk.is_empty()),
+		};
+		line.extend_from_slice(&chunk[..n]);
+		r.consume(n);
+		if done {
+			break;
+		}
+	}
+	let line =
+		String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+	buf.push_str(&line);
+	Ok(line.len())
+}
+
+// A running script also holds the lines continuing its commands
+fn read_command(s: &mut State, buf: &mut String) -> io::Result<usize> {
+	match s.commands {
+		Some(ref mut r) => r.read_line(buf),
+		None => read_input(s, buf),
+	}
+}
+
+// The line typed while interrupted is dropped along with the earlier input
+fn input_to_buffer(s: &mut State, buf: &mut Buffer) -> Result<()> {
+	let mut input = String::new();
+	loop {
+		// EOF ends input mode just like the terminator
+		match read_input(s, &mut input) {
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+				interrupted(s);
+				return Err(CommandError::Interrupted);
+			}
+			Ok(0) | Err(_) => return Ok(()),
+			Ok(_) => {}
+		}
+		if interrupted(s) {
+			return Err(CommandError::Interrupted);
+		}
+		if parse_terminator(&input).is_ok() {
+			return Ok(());
+		}
+
+		// A lone \. inserts a line containing just the dot
+		let line = input.strip_suffix('\n').unwrap_or(&input);
+		buf.push(String::from(if line == "\\." { "." } else { line }));
+		input.clear();
+	}
+}
+
+fn input_to_commands(s: &mut State, cmds: &mut Vec<String>) {
+	let mut input = String::new();
+	while let Some(cmd) = cmds.last_mut().filter(|c| c.ends_with('\\')) {
+		// Inside a replacement the backslash escapes the newline instead
+		let open = matches!(
+			parse_command(&format!("{}\n", cmd)),
+			Err(nom::Err::Incomplete(_))
+		);
+		if open {
+			cmd.push('\n');
+		} else {
+			cmd.pop();
+		}
+		match read_command(s, &mut input) {
+			Ok(0) | Err(_) => return,
+			Ok(_) => {}
+		}
+		let line = input.trim_end_matches('\n');
+		match cmds.last_mut() {
+			Some(cmd) if open => cmd.push_str(line),
+			_ => cmds.push(String::from(line)),
+		}
+		input.clear();
+	}
+}
+
+fn extract_addr_range(s: &mut State, range: Option<AddressRange>) -> Result<(usize, usize)> {
+	match range {
+		// A single address is only resolved once
+		Some(AddressRange::Range(f, t)) if f == t => {
+			let line = line_to_index(s, f)?;
+			Ok((line, line))
+		}
+		Some(AddressRange::Range(f, t)) => {
+			let from = line_to_index(s, f)?;
+			let to = line_to_index(s, t)?;
+			if from > to {
+				return Err(CommandError::InvalidAddress);
+			}
+			Ok((from, to))
+		}
+		// The first address becomes the current line before the second is resolved
+		Some(AddressRange::Step(f, t)) => {
+			let from = line_to_index(s, f)?;
+			s.buffer.curline = is_valid(s, from)?;
+			let to = line_to_index(s, t)?;
+			if from > to {
+				return Err(CommandError::InvalidAddress);
+			}
+			Ok((from, to))
+		}
+		None => Ok((
+			line_to_index(s, Address::Rel(0))?,
+			line_to_index(s, Address::Rel(0))?,
+		)),
+	}
+}
+
+// Save the buffer for undo, global commands save it once for all lines. A failed
+// command changed nothing, unless it was a global stopping halfway.
+fn exec_command(
+	s: &mut State,
+	c: (Option<AddressRange>, Option<Command>, PrintFlag),
+) -> Result<()> {
+	let snap = match c.1 {
+		Some(ref com) if !s.global && !s.dry_run && com.is_undoable() => Some(s.buffer.snapshot()),
+		_ => None,
+	};
+	let global = matches!(
+		c.1,
+		Some(Command::Global(..))
+			| Some(Command::InteractiveGlobal(_))
+			| Some(Command::InteractiveInverseGlobal(_))
+			| Some(Command::InverseGlobal(..))
+	);
+	let res = run_command(s, c);
+	if let Some(snap) = snap {
+		if res.is_ok() || global {
+			save_undo(s, snap);
+		}
+	}
+	res
+}
+
+fn run_command(s: &mut State, c: (Option<AddressRange>, Option<Command>, PrintFlag)) -> Result<()> {
+	let (range, mut command, mut flags) = c;
+
+	// Line 0 addresses the top of the buffer for commands adding lines, so does $
+	// while there are no lines
+	let top = match range {
+		Some(AddressRange::Range(Address::Zero, Address::Zero)) => true,
+		Some(AddressRange::Range(Address::Last(0), Address::Last(0))) => s.buffer.is_empty(),
+		_ => false,
+	} && matches!(
+		command,
+		Some(Command::Append(_)) | Some(Command::Insert(_)) | Some(Command::Read(_))
+	);
+
+	// Counting lines gives 0 for line 0 and in an empty buffer
+	let none = matches!(command, Some(Command::CurLine))
+		&& (s.buffer.is_empty()
+			|| matches!(
+				range,
+				Some(AddressRange::Range(Address::Zero, Address::Zero))
+			));
+
+	// Without lines there is nothing to print, delete or substitute
+	if s.buffer.is_empty()
+		&& matches!(
+			command,
+			Some(Command::Change(_))
+				| Some(Command::Delete)
+				| Some(Command::Join(_))
+				| Some(Command::Print)
+				| Some(Command::PrintList)
+				| Some(Command::PrintNumbered)
+				| Some(Command::RepeatSubst)
+				| Some(Command::Substitute(..))
+		) {
+		return Err(CommandError::InvalidAddress);
+	}
+
+	// Some commands default to the whole buffer
+	let (from, to) = match (&range, &command) {
+		(None, Some(Command::AppendWrite(_)))
+		| (None, Some(Command::Count(_)))
+		| (None, Some(Command::Global(..)))
+		| (None, Some(Command::InteractiveGlobal(_)))
+		| (None, Some(Command::InteractiveInverseGlobal(_)))
+		| (None, Some(Command::InverseGlobal(..)))
+		| (None, Some(Command::Write(_)))
+		| (None, Some(Command::WriteQuit(_))) => (0, s.buffer.len().saturating_sub(1)),
+		// Reading appends to the end of the buffer
+		(None, Some(Command::Read(_))) => {
+			let last = s.buffer.len().saturating_sub(1);
+			(last, last)
+		}
+		_ if top || none => (0, 0),
+		// An empty line steps to the next line
+		(None, None) if flags == PrintFlag::None => (s.buffer.curline + 1, s.buffer.curline + 1),
+		(None, Some(Command::Scroll(_))) => (s.buffer.curline + 1, s.buffer.curline + 1),
+		_ => extract_addr_range(s, range)?,
+	};
+
+	check_restricted(s, &command)?;
+
+	// Get input if needed
+	match command {
+		Some(Command::Append(ref mut b))
+		| Some(Command::Insert(ref mut b))
+		| Some(Command::Change(ref mut b)) => input_to_buffer(s, b)?,
+		Some(Command::Global(_, ref mut cmds)) | Some(Command::InverseGlobal(_, ref mut cmds)) => {
+			input_to_commands(s, cmds)
+		}
+		_ => {}
+	};
+
+	let added = match command {
+		Some(Command::Append(ref b))
+		| Some(Command::Insert(ref b))
+		| Some(Command::Change(ref b)) => Some(b.len()),
+		_ => None,
+	};
+	let changes = command
+		.as_ref()
+		.is_some_and(|c| c.is_undoable() || matches!(c, Command::Redo | Command::Undo));
+
+	// A dry run previews substitutions, anything else changing the buffer is refused
+	if s.dry_run && command.as_ref().is_some_and(Command::is_undoable) {
+		match command {
+			Some(Command::Global(..))
+			| Some(Command::InverseGlobal(..))
+			| Some(Command::RepeatSubst)
+			| Some(Command::Substitute(..)) => {}
+			_ => return Err(CommandError::DryRun),
+		}
+	}
+
+	match command {
+		None => {
+			is_valid(s, from)?;
+			is_valid(s, to)?;
+			if flags == PrintFlag::None {
+				s.buffer.curline = is_line(from, to)?;
+				flags = print_flag_set(flags, PrintFlag::Print);
+			}
+		}
+		Some(com @ Command::Append(_)) | Some(com @ Command::Insert(_)) => {
+			let line = is_line(from, to)?;
+
+			// Without input the addressed line becomes the current line
+			s.buffer.curline = line;
+			match com {
+				Command::Append(b) | Command::Insert(b) if top => buffer_insert(s, 0, b),
+				Command::Append(b) => buffer_insert(s, cmp::min(line + 1, s.buffer.len()), b),
+				Command::Insert(b) => buffer_insert(s, line, b),
+				_ => unreachable!(),
+			}
+		}
+		Some(com @ Command::Change(_)) | Some(com @ Command::Delete) => {
+			is_valid(s, from)?;
+			is_valid(s, to)?;
+			match com {
+				// The last new line becomes current, or the one before the range
+				Command::Change(b) => {
+					let n = b.len();
+					s.buffer.replace_iter(from..(to + 1), b);
+					s.buffer.curline = cmp::min(
+						(from + n).saturating_sub(1),
+						s.buffer.len().saturating_sub(1),
+					);
+				}
+				Command::Delete => {
+					s.buffer
+						.replace_iter(from..(to + 1), iter::empty::<String>());
+					// The line after the range becomes current, or the new last line
+					s.buffer.curline = cmp::min(from, s.buffer.len().saturating_sub(1));
+				}
+				_ => unreachable!(),
+			};
+		}
+		Some(Command::AppendWrite(f)) => {
+			let len = append_file(s, f.as_ref().unwrap_or(&s.file), from, to)?;
+			print_count(s, len);
+		}
+		Some(Command::Comment) => {
+			// The address still sets the current line
+			if to != s.buffer.curline {
+				s.buffer.curline = is_valid(s, to)?;
+			}
+		}
+		// Like g/re/ but only counts, to check a range before changing it
+		Some(Command::Count(re)) => {
+			let n = mark_lines(s, from, to, &re, false)?.len();
+			outputln!(s, "{}", n);
+		}
+		// Prints the number of the addressed line without making it current
+		Some(Command::CurLine) => {
+			outputln!(s, "{}", if none { 0 } else { to + 1 });
+		}
+		// Another file joins the ring next to the current one, nothing is discarded
+		Some(Command::Edit(Some(f)))
+			if !f.starts_with('!') && !s.file.is_empty() && f != s.file =>
+		{
+			check_path(s, &f)?;
+			let len = match s.ring.iter().position(|(name, _)| *name == f) {
+				// A file already in the ring is switched to instead of read again
+				Some(i) => {
+					let (f, buf) = s.ring.remove(i).ok_or(CommandError::NoOtherBuffer)?;
+					let cur = (
+						mem::replace(&mut s.file, f),
+						mem::replace(&mut s.buffer, buf),
+					);
+					s.ring.push_front(cur);
+					s.undo.clear();
+					s.redo.clear();
+					s.buffer.byte_len()
+				}
+				None => {
+					let (buf, len) = read_to_buffer(s, &f)?;
+					let prev = mem::replace(&mut s.buffer, Buffer::new());
+					s.ring.push_front((mem::take(&mut s.file), prev));
+					edit_state(s, &f, buf);
+					len
+				}
+			};
+			print_count(s, len);
+		}
+		Some(Command::Edit(f)) => {
+			// Repeating the command discards the changes
+			if s.buffer.changed && !s.warned {
+				s.warned = true;
+				return Err(CommandError::ModifiedBuffer);
+			}
+			match f {
+				Some(c) if c.starts_with('!') => {
+					let buf = command_to_buffer(&expand_command(s, &c[1..])?)?;
+					let len = buf.byte_len();
+					let f = s.file.clone();
+					edit_state(s, &f, buf);
+					print_count(s, len);
+				}
+				Some(f) => read_file(s, &f)?,
+				None => {
+					let f = s.file.clone();
+					read_file(s, &f)?;
+				}
+			}
+		}
+		Some(Command::Exec(c)) => {
+			// A leading ! stands for the previous command, the result is echoed
+			let cmd = match c.strip_prefix('!') {
+				Some(rest) => {
+					let prev = s
+						.last_command
+						.as_ref()
+						.ok_or(CommandError::NoPreviousCommand)?;
+					format!("{}{}", prev, expand_command(s, rest)?)
+				}
+				None => expand_command(s, &c)?,
+			};
+			if cmd != c && !s.silent {
+				outputln!(s, "{}", cmd);
+			}
+			s.last_command = Some(cmd.clone());
+			process::Command::new("sh")
+				.arg("-c")
+				.arg(cmd)
+				.status()
+				.map_err(|_| CommandError::CommandFailed)?;
+			if !s.silent {
+				outputln!(s, "!");
+			}
+		}
+		Some(Command::File(Some(f))) => {
+			s.file = f;
+		}
+		Some(Command::File(None)) => {
+			if s.file.is_empty() {
+				return Err(CommandError::NoFilename);
+			}
+			outputln!(s, "{}", s.file);
+		}
+		Some(Command::Global(re, cmds)) => {
+			exec_global(s, from, to, &re, cmds, false)?;
+		}
+		Some(Command::InteractiveGlobal(re)) => {
+			exec_interactive(s, from, to, &re, false)?;
+		}
+		Some(Command::InteractiveInverseGlobal(re)) => {
+			exec_interactive(s, from, to, &re, true)?;
+		}
+		Some(Command::InverseGlobal(re, cmds)) => {
+			exec_global(s, from, to, &re, cmds, true)?;
+		}
+		Some(Command::Help) => {
+			s.verbose = !s.verbose;
+			if s.verbose {
+				if let Some(ref e) = s.last_error {
+					outputln!(s, "{}", e.explain());
+				}
+			}
+		}
+		Some(Command::Join(sep)) => {
+			is_valid(s, from)?;
+			// A single line is joined with the next one
+			let to = is_valid(s, if from == to { to + 1 } else { to })?;
+			s.buffer.join_range(from..=to, sep.as_deref().unwrap_or(""));
+		}
+		Some(Command::Mark(m)) => {
+			is_valid(s, from)?;
+			is_valid(s, to)?;
+			s.buffer.marks[usize::from(m)] = Some(is_line(from, to)?);
+		}
+		Some(Command::Move(dest)) => {
+			is_valid(s, from)?;
+			is_valid(s, to)?;
+			let dest = dest_to_index(s, dest)?;
+			s.buffer
+				.move_lines(from..=to, dest)
+				.ok_or(CommandError::InvalidDestination)?;
+		}
+		Some(com @ Command::Print)
+		| Some(com @ Command::PrintList)
+		| Some(com @ Command::PrintNumbered) => {
+			is_valid(s, from)?;
+			is_valid(s, to)?;
+			let flag = match com {
+				Command::Print => PrintFlag::Print,
+				Command::PrintList => PrintFlag::List,
+				Command::PrintNumbered => PrintFlag::Number,
+				_ => unreachable!(),
+			};
+			flags = print_flag_set(flag, flags);
+			s.buffer.curline = to;
+		}
+		Some(Command::PrintLastError) => {
+			if let Some(ref e) = s.last_error {
+				outputln!(s, "{}", e.explain());
+			}
+		}
+		Some(Command::Prompt) => {
+			s.prompt = !s.prompt;
+		}
+		Some(Command::Scroll(n)) => {
+			let line = is_valid(s, is_line(from, to)?)?;
+
+			// The window size sticks for later z commands
+			match n {
+				Some(0) => return Err(CommandError::InvalidCount),
+				Some(n) => s.window = n,
+				None => {}
+			}
+			let to = cmp::min(line + s.window - 1, s.buffer.len() - 1);
+			print_range(s, line, to, print_flag_set(flags, PrintFlag::Print));
+			s.buffer.curline = to;
+			flags = PrintFlag::None;
+		}
+		Some(com @ Command::Substitute(..)) | Some(com @ Command::RepeatSubst) => {
+			is_valid(s, from)?;
+			is_valid(s, to)?;
+			let (r, new, n, global) = match com {
+				Command::Substitute(_, _, 0, _) => return Err(CommandError::InvalidCount),
+				Command::Substitute(re, new, n, global) => {
+					let r = get_regex(s, re.as_ref())?;
+					(r, replacement_template(&new), n as usize, global)
+				}
+				Command::RepeatSubst => s
+					.last_subst
+					.clone()
+					.ok_or(CommandError::NoPreviousSubstitution)?,
+				_ => unreachable!(),
+			};
+			s.last_subst = Some((r.clone(), new.clone(), n, global));
+			if s.dry_run {
+				return preview_subst(s, from, to, &r, &new, n, global);
+			}
+			let mut last = None;
+			let (mut i, mut to) = (from, to);
+			while i <= to {
+				let line = s.buffer.get(i).unwrap();
+				if let Some(line) = substitute(&r, line, &new, n, global) {
+					// Newlines in the replacement split the line
+					let mut lines = line.split('\n').map(String::from);
+					s.buffer.replace_line(i, lines.next().unwrap());
+					let rest: Vec<String> = lines.collect();
+					let added = rest.len();
+					if added > 0 {
+						s.buffer.replace_iter((i + 1)..(i + 1), rest);
+					}
+					i += added;
+					to += added;
+					last = Some(i);
+				}
+				i += 1;
+			}
+			s.buffer.curline = last.ok_or(CommandError::NoMatch)?;
+
+			// Only the last substituted line is printed
+			if flags != PrintFlag::None {
+				print_range(s, s.buffer.curline, s.buffer.curline, flags);
+				flags = PrintFlag::None;
+			}
+		}
+		Some(Command::Read(f)) => {
+			let (buf, len) = match f {
+				Some(c) if c.starts_with('!') => {
+					let buf = command_to_buffer(&expand_command(s, &c[1..])?)?;
+					let len = buf.byte_len();
+					(buf, len)
+				}
+				Some(f) => read_to_buffer(s, &f)?,
+				_ => {
+					let f = s.file.clone();
+					read_to_buffer(s, &f)?
+				}
+			};
+			let line = is_line(from, to)?;
+			s.buffer.curline = line;
+			let line = if top {
+				0
+			} else {
+				cmp::min(line + 1, s.buffer.len())
+			};
+			buffer_insert(s, line, buf);
+			print_count(s, len);
+		}
+		Some(Command::Transfer(dest)) => {
+			is_valid(s, from)?;
+			is_valid(s, to)?;
+			let dest = dest_to_index(s, dest)?;
+			s.buffer
+				.copy_lines(from..=to, dest)
+				.ok_or(CommandError::InvalidDestination)?;
+		}
+		Some(Command::NextBuffer) | Some(Command::PrevBuffer) => {
+			// Like e, switching keeps the modified buffer in the ring
+			let next = matches!(command, Some(Command::NextBuffer));
+			let (f, buf) = if next {
+				s.ring.pop_back()
+			} else {
+				s.ring.pop_front()
+			}
+			.ok_or(CommandError::NoOtherBuffer)?;
+			let cur = (
+				mem::replace(&mut s.file, f),
+				mem::replace(&mut s.buffer, buf),
+			);
+			if next {
+				s.ring.push_front(cur);
+			} else {
+				s.ring.push_back(cur);
+			}
+			// The history belonged to the other buffer
+			s.undo.clear();
+			s.redo.clear();
+			if !s.silent {
+				outputln!(s, "{}", s.file);
+			}
+		}
+		Some(Command::Redo) => {
+			let snap = s.redo.pop().ok_or(CommandError::NothingToRedo)?;
+			s.undo.push_back(s.buffer.snapshot());
+			s.buffer.restore(snap);
+		}
+		Some(Command::Undo) => {
+			let snap = s.undo.pop_back().ok_or(CommandError::NothingToUndo)?;
+			s.redo.push(s.buffer.snapshot());
+			s.buffer.restore(snap);
+		}
+		Some(Command::Write(Some(c))) if c.starts_with('!') => {
+			let len = write_command(s, &expand_command(s, &c[1..])?, from, to)?;
+			print_count(s, len);
+		}
+		Some(Command::Write(f)) => {
+			let len = write_file(s, f.as_ref().unwrap_or(&s.file), from, to)?;
+			print_count(s, len);
+			// Only writing the whole buffer saves all changes
+			if from == 0 && to + 1 >= s.buffer.len() {
+				s.buffer.changed = false;
+			}
+		}
+		Some(Command::WriteQuit(f)) => {
+			let len = write_file(s, f.as_ref().unwrap_or(&s.file), from, to)?;
+			print_count(s, len);
+			if from == 0 && to + 1 >= s.buffer.len() {
+				s.buffer.changed = false;
+			}
+			// Other modified buffers in the ring warn like q
+			if !s.warned && (s.buffer.changed || s.ring.iter().any(|(_, b)| b.changed)) {
+				s.warned = true;
+				return Err(CommandError::ModifiedBuffer);
+			}
+			s.quit = true;
+		}
+		Some(Command::Quit) => {
+			if !s.warned && (s.buffer.changed || s.ring.iter().any(|(_, b)| b.changed)) {
+				s.warned = true;
+				return Err(CommandError::ModifiedBuffer);
+			}
+			s.quit = true;
+		}
+		Some(Command::QuitForce) => {
+			s.quit = true;
+		}
+	}
+	// Input mode prints the new lines, other changes the current line, as the
+	// addressed ones may have moved or be gone
+	let (from, to) = match added {
+		Some(n) if n > 0 => (s.buffer.curline + 1 - n, s.buffer.curline),
+		Some(_) => (s.buffer.curline, s.buffer.curline),
+		None if changes => (s.buffer.curline, s.buffer.curline),
+		None => (from, to),
+	};
+	if flags != PrintFlag::None {
+		print_range(s, from, to, flags);
+	}
+	Ok(())
+}
+
+fn exec_global(
+	s: &mut State,
+	from: usize,
+	to: usize,
+	re: &str,
+	cmds: Vec<String>,
+	invert: bool,
+) -> Result<()> {
+	let lines = mark_lines(s, from, to, re, invert)?;
+
+	// Deleting line by line is quadratic, g/re/d is common enough for a shortcut
+	if cmds.len() == 1 && cmds[0] == "d" && !s.dry_run {
+		s.buffer.delete_lines(&lines);
+		return Ok(());
+	}
+	s.buffer.set_global(lines);
+
+	let cmds = if cmds.is_empty() {
+		vec![String::from("p")]
+	} else {
+		cmds
+	};
+	s.global = true;
+	let res = exec_marked(s, &cmds);
+	s.global = false;
+	s.buffer.set_global(iter::empty());
+	res
+}
+
+// Mark lines first, the buffer keeps track of them while they move
+fn mark_lines(s: &mut State, from: usize, to: usize, re: &str, invert: bool) -> Result<Vec<usize>> {
+	if s.global {
+		return Err(CommandError::NestedGlobal);
+	}
+	let r = get_regex(s, Some(&String::from(re)).filter(|re| !re.is_empty()))?;
+	Ok(s.buffer
+		.range(from, to)
+		.enumerate()
+		.filter(|(_, l)| r.is_match(l) != invert)
+		.map(|(i, _)| from + i)
+		.collect())
+}
+
+// Prints each marked line and runs the command typed for it. An empty line
+// skips it, & repeats the previous command.
+fn exec_interactive(s: &mut State, from: usize, to: usize, re: &str, invert: bool) -> Result<()> {
+	let lines = mark_lines(s, from, to, re, invert)?;
+	s.buffer.set_global(lines);
+	s.global = true;
+	let res = exec_typed(s);
+	s.global = false;
+	s.buffer.set_global(iter::empty());
+	res
+}
+
+fn exec_typed(s: &mut State) -> Result<()> {
+	let mut prev: Option<String> = None;
+	while let Some(line) = s.buffer.next_global() {
+		s.buffer.curline = line;
+		print_range(s, line, line, PrintFlag::Print);
+		let _ = s.out.flush();
+
+		let mut input = String::new();
+		match read_command(s, &mut input) {
+			Ok(0) | Err(_) => return Ok(()),
+			Ok(_) => {}
+		}
+		if interrupted(s) {
+			return Err(CommandError::Interrupted);
+		}
+		let cmd = match input.as_str() {
+			"\n" => continue,
+			"&\n" => prev.clone().ok_or(CommandError::NoPreviousCommand)?,
+			_ => input,
+		};
+		let (_, c) = parse_command(&cmd).or(Err(CommandError::InvalidCommand))?;
+		exec_command(s, c)?;
+		if s.quit {
+			return Ok(());
+		}
+		prev = Some(cmd);
+	}
+	Ok(())
+}
+
+// Checks and clears the interrupt flag
+fn interrupted(s: &State) -> bool {
+	s.interrupt.swap(false, Ordering::Relaxed)
+}
+
+// An interrupt stops between lines, commands already run stay applied.
+// A substitution without a match skips the line, the global only fails
+// if it matched none of the marked lines.
+fn exec_marked(s: &mut State, cmds: &[String]) -> Result<()> {
+	let (mut matched, mut missed) = (false, false);
+	while let Some(line) = s.buffer.next_global() {
+		if interrupted(s) {
+			return Err(CommandError::Interrupted);
+		}
+		s.buffer.curline = line;
+		for cmd in cmds {
+			let (_, c) =
+				parse_command(&format!("{}\n", cmd)).or(Err(CommandError::InvalidCommand))?;
+			let subst = matches!(
+				c.1,
+				Some(Command::Substitute(..)) | Some(Command::RepeatSubst)
+			);
+			match exec_command(s, c) {
+				Err(CommandError::NoMatch) if subst => {
+					missed = true;
+					break;
+				}
+				Err(e) => return Err(e),
+				Ok(()) => matched |= subst,
+			}
+			if s.quit {
+				return Ok(());
+			}
+		}
+	}
+	if missed && !matched {
+		return Err(CommandError::NoMatch);
+	}
+	Ok(())
+}
+
+// Commands continuing on the next line read it from the input
+fn parse_input(
+	s: &mut State,
+	line: &mut String,
+) -> Result<(Option<AddressRange>, Option<Command>, PrintFlag)> {
+	loop {
+		match parse_command(line) {
+			Ok((_, c)) => return Ok(c),
+			Err(nom::Err::Incomplete(_)) => match read_command(s, line) {
+				Ok(0) | Err(_) => return Err(CommandError::InvalidCommand),
+				Ok(_) => {}
+			},
+			// The parser stops at the first character it cannot use
+			Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+				return Err(match e.input.chars().next() {
+					Some(c) if c != '\n' && !is_command(c) => {
+						CommandError::UnknownCommand(command_text(line).to_string())
+					}
+					_ => CommandError::InvalidCommand,
+				})
+			}
+		}
+	}
+}
+
+/// A line editor session working on a single buffer
+pub struct Editor<'a> {
+	state: State<'a>,
+}
+
+/// The outcome of a command that did not fail
+#[derive(Debug, Default)]
+pub struct Output {
+	pub quit: bool,
+}
+
+impl<'a> Editor<'a> {
+	/// An editor printing to stdout, z pages by the terminal height
+	pub fn new() -> Self {
+		Editor {
+			state: State {
+				window: term::rows()
+					.filter(|r| *r > 1)
+					.map_or(DEFAULT_WINDOW, |r| r - 1),
+				..State::default()
+			},
+		}
+	}
+
+	/// An editor printing to out instead of stdout
+	pub fn with_output<W: Write + 'a>(out: W) -> Self {
+		Editor {
+			state: State {
+				out: Box::new(out),
+				..State::default()
+			},
+		}
+	}
+
+	/// Reads f into the buffer and makes it the current file
+	pub fn open(&mut self, f: &str) -> Result<()> {
+		let res = read_file(&mut self.state, f);
+		self.state.error_occurred |= res.is_err();
+		self.flush();
+		res
+	}
+
+	/// Parses and runs a single command line
+	pub fn execute(&mut self, line: &str) -> Result<Output> {
+		let mut line = String::from(line);
+		self.state.interrupt.store(false, Ordering::Relaxed);
+		if !line.ends_with('\n') {
+			line.push('\n');
+		}
+		let res =
+			parse_input(&mut self.state, &mut line).and_then(|c| exec_command(&mut self.state, c));
+		if let Err(ref e) = res {
+			self.state.last_error = Some(e.clone());
+			self.state.error_occurred = true;
+		}
+		// Repeating a command despite the warning must follow right away
+		if res != Err(CommandError::ModifiedBuffer) {
+			self.state.warned = false;
+		}
+		self.flush();
+		res.map(|_| Output {
+			quit: mem::replace(&mut self.state.quit, false),
+		})
+	}
+
+	/// Runs every line of script as a command, errors are reported and the script
+	/// goes on. Commands continuing on the next line read it from the script.
+	/// Returns whether the script quit.
+	pub fn run_script<R: BufRead + 'a>(&mut self, script: R) -> bool {
+		let prev = self.state.commands.replace(Box::new(script));
+		let mut quit = false;
+		loop {
+			let mut line = String::new();
+			match self.state.commands.as_mut().map(|r| r.read_line(&mut line)) {
+				Some(Ok(n)) if n > 0 => {}
+				_ => break,
+			}
+			match self.execute(&line) {
+				Ok(out) if out.quit => {
+					quit = true;
+					break;
+				}
+				Ok(_) => {}
+				Err(e) => self.report(&e),
+			}
+		}
+		self.state.commands = prev;
+		quit
+	}
+
+	/// Prints the prompt if it is enabled
+	pub fn print_prompt(&mut self) {
+		if self.state.prompt {
+			output!(self.state, "{}", self.state.prompt_str);
+			self.flush();
+		}
+	}
+
+	/// Substitutions print the changed lines instead of applying them
+	pub fn set_dry_run(&mut self, dry_run: bool) {
+		self.state.dry_run = dry_run;
+	}
+
+	/// Keeps the previous contents of overwritten files as a ~ backup, hard-linked
+	/// or copied if linking fails, the original stays in place
+	pub fn set_backup(&mut self, backup: bool) {
+		self.state.backup = backup;
+	}
+
+	/// The prompt if it is enabled
+	pub fn prompt(&self) -> Option<&str> {
+		Some(self.state.prompt_str.as_str()).filter(|_| self.state.prompt)
+	}
+
+	/// Sets and enables the prompt, an empty string selects the default
+	pub fn set_prompt(&mut self, prompt: &str) {
+		self.state.prompt_str = if prompt.is_empty() {
+			String::from(DEFAULT_PROMPT)
+		} else {
+			String::from(prompt)
+		};
+		self.state.prompt = true;
+	}
+
+	/// Reads the text of a, i and c, continued commands and the commands for G and V
+	/// from input instead of stdin
+	pub fn set_input<R: BufRead + 'a>(&mut self, input: R) {
+		self.state.input = Some(Box::new(input));
+	}
+
+	/// Interrupts input mode and global commands when flag gets set
+	pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+		self.state.interrupt = flag;
+	}
+
+	/// Keeps the buffer in a gap buffer, for editing very large files
+	pub fn set_large(&mut self, large: bool) {
+		self.state.large = large;
+		self.state.buffer.set_large(large);
+	}
+
+	/// Keeps up to depth changes for u to go back through
+	pub fn set_undo_depth(&mut self, depth: usize) {
+		self.state.undo_depth = depth;
+	}
+
+	/// Forbids shell commands and files outside the current directory
+	pub fn set_restricted(&mut self, restricted: bool) {
+		self.state.restricted = restricted;
+	}
+
+	/// Suppresses byte counts and the ! after shell commands
+	pub fn set_silent(&mut self, silent: bool) {
+		self.state.silent = silent;
+	}
+
+	/// Prints the error marker, explained in verbose mode
+	pub fn report(&mut self, e: &CommandError) {
+		outputln!(self.state, "?");
+		if self.state.verbose {
+			outputln!(self.state, "{}", e.explain());
+		}
+		self.flush();
+	}
+
+	pub fn modified(&self) -> bool {
+		self.state.buffer.changed || self.state.ring.iter().any(|(_, b)| b.changed)
+	}
+
+	/// Scripts run with -s fail if any command failed
+	pub fn exit_status(&self) -> i32 {
+		if self.state.silent && self.state.error_occurred {
+			1
+		} else {
+			0
+		}
+	}
+
+	fn flush(&mut self) {
+		let _ = self.state.out.flush();
+	}
+}
+
+impl Default for Editor<'_> {
+	fn default() -> Self {
+		Editor::new()
+	}
+}
@@ -0,0 +1,242 @@
+/*
+ * Copyright (c) 2021-2022 Tobias Heider <me@tobhe.de>
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::cmp;
+use std::iter::FusedIterator;
+use std::mem;
+use std::slice;
+
+// Spare slots added when the gap runs full
+const GAP_MIN: usize = 1024;
+
+// The lines of a buffer. Usually a plain vector, in gap mode the empty slots
+// buf[start..end] follow the last edit so that inserts and deletes near it
+// only move the lines in between instead of the whole tail.
+#[derive(Clone, Debug)]
+pub struct Lines {
+	buf: Vec<String>,
+	start: usize,
+	end: usize,
+	gap: bool,
+}
+
+impl Lines {
+	pub const fn new() -> Self {
+		Lines {
+			buf: Vec::new(),
+			start: 0,
+			end: 0,
+			gap: false,
+		}
+	}
+
+	pub fn reserve(&mut self, n: usize) {
+		self.buf.reserve(n);
+	}
+
+	// Keeps the allocation and the mode
+	pub fn clear(&mut self) {
+		self.buf.clear();
+		self.start = 0;
+		self.end = 0;
+	}
+
+	// Switching back closes the gap
+	pub fn set_gap(&mut self, gap: bool) {
+		if self.gap && !gap {
+			self.close();
+		}
+		self.gap = gap;
+	}
+
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.buf.len() - (self.end - self.start)
+	}
+
+	#[inline]
+	fn index(&self, i: usize) -> usize {
+		if i < self.start {
+			i
+		} else {
+			i + (self.end - self.start)
+		}
+	}
+
+	pub fn get(&self, i: usize) -> Option<&String> {
+		if i < self.len() {
+			self.buf.get(self.index(i))
+		} else {
+			None
+		}
+	}
+
+	pub fn get_mut(&mut self, i: usize) -> Option<&mut String> {
+		if i < self.len() {
+			let i = self.index(i);
+			self.buf.get_mut(i)
+		} else {
+			None
+		}
+	}
+
+	// Replaces lines from..to with items
+	pub fn splice<I: IntoIterator<Item = String>>(&mut self, from: usize, to: usize, items: I) {
+		if !self.gap {
+			self.buf.splice(from..to, items);
+			self.start = self.buf.len();
+			self.end = self.start;
+			return;
+		}
+		self.move_gap(to);
+		for line in &mut self.buf[from..to] {
+			drop(mem::take(line));
+		}
+		self.start = from;
+		for item in items {
+			if self.start == self.end {
+				self.grow();
+			}
+			self.buf[self.start] = item;
+			self.start += 1;
+		}
+	}
+
+	// Lines from..to as one slice, the gap is moved out of the way if needed
+	pub fn slice_mut(&mut self, from: usize, to: usize) -> &mut [String] {
+		if self.start > from && self.start < to {
+			self.move_gap(to);
+		}
+		let (from, to) = if self.start <= from {
+			(self.index(from), self.index(from) + (to - from))
+		} else {
+			(from, to)
+		};
+		&mut self.buf[from..to]
+	}
+
+	pub fn retain<F: FnMut(&String) -> bool>(&mut self, f: F) {
+		self.close();
+		self.buf.retain(f);
+		self.start = self.buf.len();
+		self.end = self.start;
+	}
+
+	pub fn into_vec(mut self) -> Vec<String> {
+		self.close();
+		self.buf
+	}
+
+	// The lines from..to, which must lie within the buffer
+	pub fn range(&self, from: usize, to: usize) -> Iter<'_> {
+		let gap = self.end - self.start;
+		Iter {
+			front: self.buf[cmp::min(from, self.start)..cmp::min(to, self.start)].iter(),
+			back: self.buf[cmp::max(from, self.start) + gap..cmp::max(to, self.start) + gap].iter(),
+		}
+	}
+
+	#[inline]
+	pub fn iter(&self) -> Iter<'_> {
+		Iter {
+			front: self.buf[..self.start].iter(),
+			back: self.buf[self.end..].iter(),
+		}
+	}
+
+	fn move_gap(&mut self, pos: usize) {
+		let gap = self.end - self.start;
+		if pos < self.start {
+			self.buf[pos..self.end].rotate_right(gap);
+		} else if pos > self.start {
+			self.buf[self.start..pos + gap].rotate_left(gap);
+		}
+		self.start = pos;
+		self.end = pos + gap;
+	}
+
+	fn grow(&mut self) {
+		let n = cmp::max(GAP_MIN, self.len() / 4);
+		self.buf.splice(
+			self.end..self.end,
+			std::iter::repeat_with(String::new).take(n),
+		);
+		self.end += n;
+	}
+
+	fn close(&mut self) {
+		if self.start != self.end {
+			self.move_gap(self.len());
+			self.buf.truncate(self.start);
+			self.end = self.start;
+		}
+	}
+}
+
+impl From<Vec<String>> for Lines {
+	fn from(buf: Vec<String>) -> Self {
+		Lines {
+			start: buf.len(),
+			end: buf.len(),
+			buf,
+			gap: false,
+		}
+	}
+}
+
+// The lines before and after the gap
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+	front: slice::Iter<'a, String>,
+	back: slice::Iter<'a, String>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+	type Item = &'a String;
+
+	#[inline]
+	fn next(&mut self) -> Option<&'a String> {
+		self.front.next().or_else(|| self.back.next())
+	}
+
+	#[inline]
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		let n = self.front.len() + self.back.len();
+		(n, Some(n))
+	}
+
+	// Skipping ahead must not walk the lines one by one
+	fn nth(&mut self, n: usize) -> Option<&'a String> {
+		let front = self.front.len();
+		if n < front {
+			self.front.nth(n)
+		} else {
+			self.front = [].iter();
+			self.back.nth(n - front)
+		}
+	}
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+	#[inline]
+	fn next_back(&mut self) -> Option<&'a String> {
+		self.back.next_back().or_else(|| self.front.next_back())
+	}
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+impl FusedIterator for Iter<'_> {}
@@ -19,26 +19,31 @@ extern crate nom;
 use crate::buffer::Buffer;
 use nom::{
 	branch::alt,
-	character::complete::{anychar, char, i32, newline, none_of},
+	bytes::complete::{tag, take_till},
+	character::complete::{anychar, char, digit1, newline, space0, u32},
 	character::is_newline,
-	combinator::opt,
+	combinator::{map, opt, verify},
 	error::{Error, ErrorKind},
 	multi::{many0, many1},
-	sequence::{preceded, terminated, tuple},
-	Err, IResult, InputTakeAtPosition,
+	sequence::{delimited, preceded, terminated, tuple},
+	Err, IResult, InputTakeAtPosition, Needed,
 };
 
+#[derive(Debug, PartialEq)]
 pub enum AddressRange {
 	Range(Address, Address), // (.,.)	Address range
-	Next(Option<String>),    // /re/	Next line containing the regex
-	Prev(Option<String>),    // ?re?	Previous line containing the regex
+	Step(Address, Address),  // (.;.)	Range with the second address relative to the first
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Address {
-	Abs(i32), // N		Nth line in the buffer
-	Rel(i32), // +-N	Nth next or previous line
-	Mark(u8), // 'x		Line previosly marked with x
+	Abs(i32),                  // N		Nth line in the buffer
+	Last(i32),                 // $+-N	Offset from the last line
+	Rel(i32),                  // +-N	Nth next or previous line
+	Mark(u8, i32),             // 'x+-N	Offset from the line previosly marked with x
+	Next(Option<String>, i32), // /re/+-N	Offset from the next line containing the regex
+	Prev(Option<String>, i32), // ?re?+-N	Offset from the previous line containing the regex
+	Zero,                      // 0		Before the first line, only valid to insert lines
 }
 
 /*
@@ -47,20 +52,66 @@ pub enum Address {
  */
 #[derive(Debug)]
 pub enum Command {
-	Append(Buffer),        // (.)a		Append text to the buffer
-	Change(Buffer),        // (.,.)c	Change line in buffer
-	CurLine,               // =		Print line number
-	Delete,                // (.,.)d	Delete lines
-	Edit(Option<String>),  // e file	Edit file
-	Exec(String),          // !cmd		Execute command
-	File(String),          // f file        Set default filename
-	Help,                  // H		Toggle error explanations
-	Insert(Buffer),        // (.)i		Insert text before current line
-	Mark(u8),              // kx		Marks a line with a lower case letter
-	Prompt,                // P		Enable * prompt
-	Read(Option<String>),  // ($)r		Reads file to after the addressed line
-	Write(Option<String>), // w file	Write buffer to file
-	Quit,                  // q		Quit
+	Append(Buffer),                                // (.)a		Append text to the buffer
+	AppendWrite(Option<String>),                   // (1,$)W file	Append lines to file
+	Change(Buffer),                                // (.,.)c	Change line in buffer
+	Comment,                                       // #		Comment until end of line
+	Count(String),                                 // (1,$)=/re/	Count matching lines
+	CurLine,                                       // =		Print line number
+	Delete,                                        // (.,.)d	Delete lines
+	Edit(Option<String>),                          // e file	Edit file
+	Exec(String),                                  // !cmd		Execute command
+	File(Option<String>),                          // f file        Set or print the default filename
+	Global(String, Vec<String>),                   // (1,$)g/re/cmd	Run cmd on matching lines
+	Help,                                          // H		Toggle error explanations
+	Insert(Buffer),                                // (.)i		Insert text before current line
+	InteractiveGlobal(String),                     // (1,$)G/re/	Edit matching lines one by one
+	InteractiveInverseGlobal(String),              // (1,$)V/re/	Edit non-matching lines one by one
+	InverseGlobal(String, Vec<String>),            // (1,$)v/re/cmd	Run cmd on non-matching lines
+	Join(Option<String>),                          // (.,.+1)j sep	Join lines, with sep in between
+	Mark(u8),                                      // kx		Marks a line with a lower case letter
+	Move(Address),                                 // (.,.)m(.)	Move lines to after the address
+	NextBuffer,                                    // bn		Switch to the next file
+	PrevBuffer,                                    // bp		Switch to the previous file
+	Print,                                         // (.,.)p	Print lines
+	PrintLastError,                                // h		Explain the last error
+	PrintList,                                     // (.,.)l	Print lines unambiguously
+	PrintNumbered,                                 // (.,.)n	Print lines with line numbers
+	Prompt,                                        // P		Enable * prompt
+	Read(Option<String>),                          // ($)r		Reads file to after the addressed line
+	Redo,                                          // U		Redo the last undone change
+	RepeatSubst,                                   // (.,.)&		Repeat the last substitution
+	Scroll(Option<usize>),                         // (.+1)zN	Print N lines, the last becomes current
+	Substitute(Option<String>, String, u32, bool), // (.,.)s/re/new/Ng	Substitute the Nth match
+	Transfer(Address),                             // (.,.)t(.)	Copy lines to after the address
+	Undo,                                          // u		Undo the last change
+	Write(Option<String>),                         // w file	Write buffer to file
+	WriteQuit(Option<String>),                     // wq file	Write buffer to file and quit
+	Quit,                                          // q		Quit
+	QuitForce,                                     // Q		Quit without checking for changes
+}
+
+impl Command {
+	// Commands changing the buffer save it for undo
+	pub fn is_undoable(&self) -> bool {
+		matches!(
+			self,
+			Command::Append(_)
+				| Command::Change(_)
+				| Command::Delete
+				| Command::Global(..)
+				| Command::Insert(_)
+				| Command::InteractiveGlobal(_)
+				| Command::InteractiveInverseGlobal(_)
+				| Command::InverseGlobal(..)
+				| Command::Join(_)
+				| Command::Move(_)
+				| Command::Read(_)
+				| Command::RepeatSubst
+				| Command::Substitute(..)
+				| Command::Transfer(_)
+		)
+	}
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -68,38 +119,59 @@ pub enum PrintFlag {
 	None,
 	Print,
 	Number,
+	List,
 }
 
+// Commands and their suffixes combine into one flag. p only prints, so any
+// other flag replaces it, while the first of n and l is kept: np and pn
+// number, nl numbers and ln lists.
 pub fn print_flag_set(fs: PrintFlag, flag: PrintFlag) -> PrintFlag {
-	if fs == PrintFlag::None || (fs == PrintFlag::Print && flag == PrintFlag::Number) {
+	if flag == PrintFlag::None {
+		fs
+	} else if fs == PrintFlag::None || fs == PrintFlag::Print {
 		flag
 	} else {
 		fs
 	}
 }
 
+// The command as typed, without its address
+pub fn command_text(i: &str) -> &str {
+	let rest: IResult<&str, _> = tuple((space0, opt(parse_address_range), space0))(i);
+	let cmd = rest.map_or(i, |(cmd, _)| cmd);
+	cmd.lines().next().unwrap_or("")
+}
+
 pub fn parse_command(i: &str) -> IResult<&str, (Option<AddressRange>, Option<Command>, PrintFlag)> {
-	let (i, (r, c, f)) = terminated(
+	// Blanks may separate the address, the command and its flags
+	let (i, (_, r, _, c, f)) = terminated(
 		tuple((
+			space0,
 			opt(parse_address_range),
+			space0,
 			opt(alt((
+				parse_count_cmd,
+				parse_join_cmd,
 				parse_simple_cmd,
 				parse_mark_cmd,
+				parse_scroll_cmd,
+				parse_move_cmd,
+				parse_subst_cmd,
+				parse_global_cmd,
+				parse_interactive_cmd,
+				parse_write_quit_cmd,
+				parse_buffer_cmd,
 				parse_file_cmd,
 				parse_exec_cmd,
+				parse_comment,
 			))),
-			many0(parse_flag),
+			many0(preceded(space0, parse_flag)),
 		)),
-		newline,
+		preceded(space0, newline),
 	)(i)?;
 	Ok((
 		i,
-		(
-			r,
-			c,
-			f.into_iter()
-				.fold(PrintFlag::None, |fs, flag| print_flag_set(fs, flag)),
-		),
+		(r, c, f.into_iter().fold(PrintFlag::None, print_flag_set)),
 	))
 }
 
@@ -110,18 +182,31 @@ fn parse_simple_cmd(i: &str) -> IResult<&str, Command> {
 		'a' => Command::Append(Buffer::new()),
 		'c' => Command::Change(Buffer::new()),
 		'd' => Command::Delete,
+		'h' => Command::PrintLastError,
 		'H' => Command::Help,
 		'i' => Command::Insert(Buffer::new()),
+		'l' => Command::PrintList,
+		'n' => Command::PrintNumbered,
+		'p' => Command::Print,
 		'P' => Command::Prompt,
 		'q' => Command::Quit,
+		'Q' => Command::QuitForce,
+		'u' => Command::Undo,
+		'U' => Command::Redo,
 		'=' => Command::CurLine,
+		'&' => Command::RepeatSubst,
 		_ => return Err(Err::Error(Error::new("line", ErrorKind::Char))),
 	};
 	Ok((i, cmd))
 }
 
+fn parse_scroll_cmd(i: &str) -> IResult<&str, Command> {
+	let (i, n) = preceded(char('z'), opt(u32))(i)?;
+	Ok((i, Command::Scroll(n.map(|n| n as usize))))
+}
+
 fn parse_mark_cmd(i: &str) -> IResult<&str, Command> {
-	let (i, c) = preceded(char('m'), anychar)(i)?;
+	let (i, c) = preceded(char('k'), anychar)(i)?;
 	let c = c as u8;
 	if c > 0x60 && c < 0x7b {
 		Ok((i, Command::Mark(c - 0x61)))
@@ -130,30 +215,174 @@ fn parse_mark_cmd(i: &str) -> IResult<&str, Command> {
 	}
 }
 
+fn parse_move_cmd(i: &str) -> IResult<&str, Command> {
+	let (i, (c, a)) = tuple((alt((char('m'), char('t'))), parse_address))(i)?;
+	match c {
+		'm' => Ok((i, Command::Move(a))),
+		't' => Ok((i, Command::Transfer(a))),
+		_ => Err(Err::Error(Error::new("line", ErrorKind::Char))),
+	}
+}
+
+fn parse_subst_cmd(i: &str) -> IResult<&str, Command> {
+	let (i, d) = preceded(char('s'), parse_delimiter)(i)?;
+	let (i, (re, _, new, _, n, f)) = tuple((
+		parse_pattern(d),
+		char(d),
+		parse_replacement(d),
+		opt(char(d)),
+		opt(u32),
+		many0(alt((char('g'), char('I')))),
+	))(i)?;
+	Ok((
+		i,
+		Command::Substitute(
+			Some(re)
+				.filter(|re| !re.is_empty())
+				.map(|re| ignore_case(re, f.contains(&'I'))),
+			new,
+			n.unwrap_or(1),
+			f.contains(&'g'),
+		),
+	))
+}
+
+// The I flag matches case insensitively, unless the pattern already says so
+fn ignore_case(re: String, icase: bool) -> String {
+	if icase && !re.starts_with("(?i)") {
+		format!("(?i){}", re)
+	} else {
+		re
+	}
+}
+
+fn parse_global_cmd(i: &str) -> IResult<&str, Command> {
+	let (i, (c, d)) = tuple((alt((char('g'), char('v'))), parse_delimiter))(i)?;
+	let (i, (re, _, cmd)) = tuple((parse_pattern(d), char(d), opt(parse_path)))(i)?;
+	let cmds = cmd.into_iter().map(ToString::to_string).collect();
+	match c {
+		'g' => Ok((i, Command::Global(re, cmds))),
+		'v' => Ok((i, Command::InverseGlobal(re, cmds))),
+		_ => Err(Err::Error(Error::new("line", ErrorKind::Char))),
+	}
+}
+
+// The separator is quoted or the rest of the line
+fn parse_join_cmd(i: &str) -> IResult<&str, Command> {
+	let quoted = delimited(char('"'), take_till(|c| c == '"' || c == '\n'), char('"'));
+	let (i, sep) = preceded(
+		char('j'),
+		opt(preceded(char(' '), alt((quoted, parse_path)))),
+	)(i)?;
+	Ok((i, Command::Join(sep.map(ToString::to_string))))
+}
+
+fn parse_count_cmd(i: &str) -> IResult<&str, Command> {
+	let (i, d) = preceded(char('='), parse_delimiter)(i)?;
+	let (i, (re, _)) = tuple((parse_pattern(d), opt(char(d))))(i)?;
+	Ok((i, Command::Count(re)))
+}
+
+// The commands for each line are read interactively
+fn parse_interactive_cmd(i: &str) -> IResult<&str, Command> {
+	let (i, (c, d)) = tuple((alt((char('G'), char('V'))), parse_delimiter))(i)?;
+	let (i, (re, _)) = tuple((parse_pattern(d), opt(char(d))))(i)?;
+	match c {
+		'G' => Ok((i, Command::InteractiveGlobal(re))),
+		'V' => Ok((i, Command::InteractiveInverseGlobal(re))),
+		_ => Err(Err::Error(Error::new("line", ErrorKind::Char))),
+	}
+}
+
+// Any character but alphanumerics, whitespace and backslash delimits a pattern
+fn parse_delimiter(i: &str) -> IResult<&str, char> {
+	verify(anychar, |c: &char| {
+		!c.is_alphanumeric() && !c.is_whitespace() && *c != '\\'
+	})(i)
+}
+
+fn parse_pattern(d: char) -> impl Fn(&str) -> IResult<&str, String> {
+	move |i: &str| parse_delimited(i, d, false)
+}
+
+// Replacements may span lines by escaping the newline
+fn parse_replacement(d: char) -> impl Fn(&str) -> IResult<&str, String> {
+	move |i: &str| parse_delimited(i, d, true)
+}
+
+// Reads up to an unescaped delimiter or newline, \d matches the delimiter literally
+fn parse_delimited(i: &str, d: char, multiline: bool) -> IResult<&str, String> {
+	let mut res = String::new();
+	let mut chars = i.char_indices();
+	while let Some((n, c)) = chars.next() {
+		match c {
+			'\\' => match chars.next() {
+				Some((_, c)) if c == d => res.push_str(&regex::escape(&c.to_string())),
+				// The next line has not been read yet
+				Some((m, '\n')) if multiline && m + 1 == i.len() => {
+					return Err(Err::Incomplete(Needed::Unknown))
+				}
+				Some((_, '\n')) if multiline => res.push('\n'),
+				Some((_, '\n')) | None => return Ok((&i[n..], res)),
+				Some((_, c)) => {
+					res.push('\\');
+					res.push(c);
+				}
+			},
+			'\n' => return Ok((&i[n..], res)),
+			c if c == d => return Ok((&i[n..], res)),
+			c => res.push(c),
+		}
+	}
+	Ok(("", res))
+}
+
 fn parse_file_cmd(i: &str) -> IResult<&str, Command> {
 	let (i, (c, s)) = tuple((anychar, opt(preceded(char(' '), parse_path))))(i)?;
 	let cmd = match c {
 		'e' => Command::Edit(s.map(ToString::to_string)),
-		'f' => Command::File(
-			s.ok_or(Err::Error(Error::new("line", ErrorKind::Char)))?
-				.to_string(),
-		),
+		'f' => Command::File(s.map(ToString::to_string)),
 		'r' => Command::Read(s.map(ToString::to_string)),
 		'w' => Command::Write(s.map(ToString::to_string)),
+		'W' => Command::AppendWrite(s.map(ToString::to_string)),
 		_ => return Err(Err::Error(Error::new("line", ErrorKind::Char))),
 	};
 	Ok((i, cmd))
 }
 
+fn parse_write_quit_cmd(i: &str) -> IResult<&str, Command> {
+	let (i, s) = preceded(tag("wq"), opt(preceded(char(' '), parse_path)))(i)?;
+	Ok((i, Command::WriteQuit(s.map(ToString::to_string))))
+}
+
+fn parse_buffer_cmd(i: &str) -> IResult<&str, Command> {
+	let (i, c) = alt((tag("bn"), tag("bp")))(i)?;
+	let cmd = match c {
+		"bn" => Command::NextBuffer,
+		_ => Command::PrevBuffer,
+	};
+	Ok((i, cmd))
+}
+
 fn parse_exec_cmd(i: &str) -> IResult<&str, Command> {
 	let (i, s) = preceded(char('!'), parse_path)(i)?;
 	Ok((i, Command::Exec(s.to_string())))
 }
 
+fn parse_comment(i: &str) -> IResult<&str, Command> {
+	let (i, _) = preceded(char('#'), opt(parse_path))(i)?;
+	Ok((i, Command::Comment))
+}
+
 fn parse_path(i: &str) -> IResult<&str, &str> {
 	i.split_at_position1_complete(|item| is_newline(item as u8), ErrorKind::Fail)
 }
 
+// A command parser failing on one of these rejected its arguments, not the command
+pub fn is_command(c: char) -> bool {
+	"!#&=GHPQUVWabcdefghijklmnpqrstuvwz".contains(c)
+}
+
 // Insert Mode
 pub fn parse_terminator(i: &str) -> IResult<&str, ()> {
 	let (i, _) = terminated(char('.'), newline)(i)?;
@@ -163,87 +392,150 @@ pub fn parse_terminator(i: &str) -> IResult<&str, ()> {
 // Helpers
 fn parse_address_range(i: &str) -> IResult<&str, AddressRange> {
 	alt((
-		parse_special_range,
 		parse_tuple_range,
+		parse_step_range,
+		parse_special_range,
 		parse_simple_range,
-		parse_regex,
 	))(i)
 }
 
-fn parse_regex(i: &str) -> IResult<&str, AddressRange> {
-	let (i, (c, s, _)) = alt((
-		tuple((char('/'), opt(many1(none_of("/?\n"))), opt(char('/')))),
-		tuple((char('?'), opt(many1(none_of("/?\n"))), opt(char('?')))),
+fn parse_regex_addr(i: &str) -> IResult<&str, Address> {
+	let (i, (c, s, icase, offsets)) = alt((
+		tuple((
+			char('/'),
+			parse_pattern('/'),
+			opt(preceded(char('/'), opt(char('I')))),
+			many0(parse_offset),
+		)),
+		tuple((
+			char('?'),
+			parse_pattern('?'),
+			opt(preceded(char('?'), opt(char('I')))),
+			many0(parse_offset),
+		)),
 	))(i)?;
+	let s = Some(s)
+		.filter(|re| !re.is_empty())
+		.map(|re| ignore_case(re, icase.flatten().is_some()));
+	let offset = sum_offsets(offsets);
 	match c {
-		'/' => Ok((i, AddressRange::Next(s.map(|re| re.into_iter().collect())))),
-		'?' => Ok((i, AddressRange::Prev(s.map(|re| re.into_iter().collect())))),
-		_ => return Err(Err::Error(Error::new("line", ErrorKind::Char))),
+		'/' => Ok((i, Address::Next(s, offset))),
+		'?' => Ok((i, Address::Prev(s, offset))),
+		_ => Err(Err::Error(Error::new("line", ErrorKind::Char))),
 	}
 }
 
+// A sign without a number counts as one line, ^ is the same as -
+fn parse_offset(i: &str) -> IResult<&str, i32> {
+	let (i, (c, n)) = tuple((parse_sign, opt(parse_number)))(i)?;
+	let n = n.unwrap_or(1);
+	Ok((i, if c == '+' { n } else { -n }))
+}
+
+// Numbers too large for a line saturate, they fail to resolve later
+fn parse_number(i: &str) -> IResult<&str, i32> {
+	map(digit1, |d: &str| d.parse().unwrap_or(i32::MAX))(i)
+}
+
+// Huge sums saturate and fail to resolve instead of overflowing
+fn sum_offsets(offsets: Vec<i32>) -> i32 {
+	offsets.into_iter().fold(0, i32::saturating_add)
+}
+
 fn parse_special_range(i: &str) -> IResult<&str, AddressRange> {
 	let (i, c) = anychar(i)?;
 	let range = match c {
-		'%' | ',' => AddressRange::Range(Address::Abs(0), Address::Abs(-1)),
-		';' => AddressRange::Range(Address::Rel(0), Address::Abs(-1)),
+		'%' => AddressRange::Range(Address::Abs(0), Address::Last(0)),
 		_ => return Err(Err::Error(Error::new("line", ErrorKind::Char))),
 	};
 	Ok((i, range))
 }
 
+// A missing first address is the first line, a missing second one repeats the first
 fn parse_tuple_range(i: &str) -> IResult<&str, AddressRange> {
-	let (i, f) = parse_address(i)?;
-	let (i, _) = char(',')(i)?;
-	let (i, t) = parse_address(i)?;
-	Ok((i, AddressRange::Range(f, t)))
+	let (i, (f, _, t)) = tuple((
+		opt(parse_address),
+		delimited(space0, char(','), space0),
+		opt(parse_address),
+	))(i)?;
+	let range = match (f, t) {
+		(Some(f), Some(t)) => AddressRange::Range(f, t),
+		(Some(f), None) => AddressRange::Range(f.clone(), f),
+		(None, Some(t)) => AddressRange::Range(Address::Abs(0), t),
+		(None, None) => AddressRange::Range(Address::Abs(0), Address::Last(0)),
+	};
+	Ok((i, range))
+}
+
+// Like a comma range but a missing first address is the current line
+fn parse_step_range(i: &str) -> IResult<&str, AddressRange> {
+	let (i, (f, _, t)) = tuple((
+		opt(parse_address),
+		delimited(space0, char(';'), space0),
+		opt(parse_address),
+	))(i)?;
+	let range = match (f, t) {
+		(Some(f), Some(t)) => AddressRange::Step(f, t),
+		(Some(f), None) => AddressRange::Step(f.clone(), f),
+		(None, Some(t)) => AddressRange::Step(Address::Rel(0), t),
+		(None, None) => AddressRange::Step(Address::Rel(0), Address::Last(0)),
+	};
+	Ok((i, range))
 }
 
 fn parse_simple_range(i: &str) -> IResult<&str, AddressRange> {
 	let (i, f) = parse_address(i)?;
-	Ok((i, AddressRange::Range(f, f)))
+	Ok((i, AddressRange::Range(f.clone(), f)))
 }
 
 fn parse_sign(i: &str) -> IResult<&str, char> {
-	alt((char('+'), char('-')))(i)
+	alt((char('+'), char('-'), char('^')))(i)
 }
 
 fn parse_address(i: &str) -> IResult<&str, Address> {
-	alt((parse_mark_addr, parse_line_addr, parse_special_addr))(i)
+	alt((
+		parse_mark_addr,
+		parse_line_addr,
+		parse_regex_addr,
+		parse_rel_addr,
+		parse_special_addr,
+	))(i)
+}
+
+// Offsets add up, --- is three lines back
+fn parse_rel_addr(i: &str) -> IResult<&str, Address> {
+	let (i, offsets) = many1(parse_offset)(i)?;
+	Ok((i, Address::Rel(sum_offsets(offsets))))
 }
 
 fn parse_special_addr(i: &str) -> IResult<&str, Address> {
 	let (i, c) = anychar(i)?;
-	let line = match c {
-		'.' => Address::Rel(0),
-		'$' => Address::Abs(-1),
-		'+' => Address::Rel(1),
-		'-' | '^' => Address::Rel(-1),
-		_ => return Err(Err::Error(Error::new("line", ErrorKind::Char))),
-	};
-	Ok((i, line))
+	match c {
+		'.' => {
+			let (i, offsets) = many0(parse_offset)(i)?;
+			Ok((i, Address::Rel(sum_offsets(offsets))))
+		}
+		'$' => {
+			let (i, offsets) = many0(parse_offset)(i)?;
+			Ok((i, Address::Last(sum_offsets(offsets))))
+		}
+		_ => Err(Err::Error(Error::new("line", ErrorKind::Char))),
+	}
 }
 
 fn parse_line_addr(i: &str) -> IResult<&str, Address> {
-	let pref = parse_sign(i);
-	let (i, o) = i32(i)?;
-	match pref {
-		Ok(_) => Ok((i, Address::Rel(o))),
-		_ => {
-			if o > 0 {
-				Ok((i, Address::Abs(o - 1)))
-			} else {
-				return Err(Err::Error(Error::new("address", ErrorKind::Fail)));
-			}
-		}
+	let (i, (o, offsets)) = tuple((parse_number, many0(parse_offset)))(i)?;
+	match o.saturating_add(sum_offsets(offsets)) {
+		0 => Ok((i, Address::Zero)),
+		o => Ok((i, Address::Abs(o.saturating_sub(1)))),
 	}
 }
 
 fn parse_mark_addr(i: &str) -> IResult<&str, Address> {
-	let (i, c) = preceded(char('\''), anychar)(i)?;
+	let (i, (c, offsets)) = tuple((preceded(char('\''), anychar), many0(parse_offset)))(i)?;
 	let c = c as u8;
 	if c > 0x60 && c < 0x7b {
-		Ok((i, Address::Mark(c - 0x61)))
+		Ok((i, Address::Mark(c - 0x61, sum_offsets(offsets))))
 	} else {
 		Err(Err::Error(Error::new("address", ErrorKind::Fail)))
 	}
@@ -253,9 +545,61 @@ fn parse_mark_addr(i: &str) -> IResult<&str, Address> {
 fn parse_flag(i: &str) -> IResult<&str, PrintFlag> {
 	let (i, c) = anychar(i)?;
 	let f = match c {
+		'l' => PrintFlag::List,
 		'n' => PrintFlag::Number,
 		'p' => PrintFlag::Print,
 		_ => return Err(Err::Error(Error::new("line", ErrorKind::Char))),
 	};
 	Ok((i, f))
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn blank_before_comma() {
+		let (_, (r, c, f)) = parse_command("1 ,5p\n").expect("cannot parse");
+		assert_eq!(
+			r,
+			Some(AddressRange::Range(Address::Abs(0), Address::Abs(4)))
+		);
+		assert!(matches!(c, Some(Command::Print)));
+		assert_eq!(f, PrintFlag::None);
+	}
+
+	#[test]
+	fn blank_before_command() {
+		let (_, (r, c, _)) = parse_command(". p\n").expect("cannot parse");
+		assert_eq!(
+			r,
+			Some(AddressRange::Range(Address::Rel(0), Address::Rel(0)))
+		);
+		assert!(matches!(c, Some(Command::Print)));
+	}
+
+	#[test]
+	fn offset_from_last_line() {
+		assert_eq!(parse_address("$-1\n"), Ok(("\n", Address::Last(-1))));
+	}
+
+	#[test]
+	fn chained_offsets() {
+		assert_eq!(parse_address("++\n"), Ok(("\n", Address::Rel(2))));
+		assert_eq!(parse_address("---\n"), Ok(("\n", Address::Rel(-3))));
+		assert_eq!(parse_address("+2+3\n"), Ok(("\n", Address::Rel(5))));
+		assert_eq!(parse_address(".+++\n"), Ok(("\n", Address::Rel(3))));
+	}
+
+	#[test]
+	fn huge_offsets_saturate() {
+		assert_eq!(
+			parse_address("+99999999999\n"),
+			Ok(("\n", Address::Rel(i32::MAX)))
+		);
+		assert_eq!(
+			parse_address("1+2147483647+2147483647\n"),
+			Ok(("\n", Address::Abs(i32::MAX - 1)))
+		);
+	}
+}
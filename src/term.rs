@@ -0,0 +1,38 @@
+/*
+ * Copyright (c) 2021-2022 Tobias Heider <me@tobhe.de>
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Number of rows of the terminal on stdout, None if it is not a terminal
+#[cfg(unix)]
+pub fn rows() -> Option<usize> {
+	let mut ws = libc::winsize {
+		ws_row: 0,
+		ws_col: 0,
+		ws_xpixel: 0,
+		ws_ypixel: 0,
+	};
+	// SAFETY: TIOCGWINSZ only writes the winsize struct passed in
+	let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) };
+	if res == 0 && ws.ws_row > 0 {
+		Some(usize::from(ws.ws_row))
+	} else {
+		None
+	}
+}
+
+#[cfg(not(unix))]
+pub fn rows() -> Option<usize> {
+	None
+}
@@ -0,0 +1,50 @@
+/*
+ * Copyright (c) 2021-2022 Tobias Heider <me@tobhe.de>
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// A path in the temp directory, unique to the test run
+fn temp_path(name: &str) -> PathBuf {
+	env::temp_dir().join(format!("red-cli-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn silent_script_prints_no_counts() {
+	let file = temp_path("silent.txt");
+	let more = temp_path("silent-more.txt");
+	let script = temp_path("silent.ed");
+	fs::write(&file, "a\nb\n").expect("cannot write fixture");
+	fs::write(&more, "c\n").expect("cannot write fixture");
+	fs::write(&script, format!("r {}\nw\n,p\nq\n", more.display())).expect("cannot write script");
+
+	let out = Command::new(env!("CARGO_BIN_EXE_red"))
+		.args(["-s", "--noinit", "--norestrict", "-f"])
+		.arg(&script)
+		.arg(&file)
+		.stdin(Stdio::null())
+		.output()
+		.expect("cannot run red");
+	let text = fs::read_to_string(&file).expect("nothing written");
+	for f in [&file, &more, &script] {
+		let _ = fs::remove_file(f);
+	}
+	assert!(out.status.success());
+	assert_eq!(String::from_utf8_lossy(&out.stdout), "a\nb\nc\n");
+	assert_eq!(text, "a\nb\nc\n");
+}
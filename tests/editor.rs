@@ -0,0 +1,399 @@
+/*
+ * Copyright (c) 2021-2022 Tobias Heider <me@tobhe.de>
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use red::{CommandError, Editor};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Cursor, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Loads text with 0a and runs the commands, typed holds the text for input mode.
+// Returns everything printed.
+fn run(text: &str, typed: &str, cmds: &[&str]) -> String {
+	let mut out = Vec::new();
+	{
+		let mut editor = Editor::with_output(&mut out);
+		editor.set_silent(true);
+		editor.set_input(Cursor::new(format!("{}.\n{}", text, typed)));
+		editor.execute("0a").expect("cannot load text");
+		for cmd in cmds {
+			let _ = editor.execute(cmd);
+		}
+	}
+	String::from_utf8(out).expect("output is not UTF-8")
+}
+
+// A path in the temp directory, unique to the test run
+fn temp_path(name: &str) -> PathBuf {
+	env::temp_dir().join(format!("red-test-{}-{}", std::process::id(), name))
+}
+
+const BLANKS: &str = "a\n\nb\n\n\nc\n";
+
+#[test]
+fn global_deletes_blank_lines() {
+	assert_eq!(run(BLANKS, "", &["g/^$/d", ",p"]), "a\nb\nc\n");
+}
+
+#[test]
+fn inverse_global_deletes_blank_lines() {
+	assert_eq!(run(BLANKS, "", &["v/./d", ",p"]), "a\nb\nc\n");
+}
+
+#[test]
+fn global_keeps_blank_lines() {
+	assert_eq!(run(BLANKS, "", &["g/./d", ",p"]), "\n\n\n");
+}
+
+#[test]
+fn inverse_global_keeps_blank_lines() {
+	assert_eq!(run(BLANKS, "", &["v/^$/d", ",p"]), "\n\n\n");
+}
+
+#[test]
+fn global_substitute_skips_unmatched_lines() {
+	assert_eq!(run("ab\na\nab\n", "", &["g/a/s/b/X/", ",p"]), "aX\na\naX\n");
+}
+
+#[test]
+fn global_substitute_splits_lines() {
+	assert_eq!(
+		run("x\ny\nx\n", "b/\n", &["g/x/s/x/a\\", ",p"]),
+		"a\nb\ny\na\nb\n"
+	);
+}
+
+#[test]
+fn global_substitute_without_any_match() {
+	let mut out = Vec::new();
+	let mut editor = Editor::with_output(&mut out);
+	editor.set_input(Cursor::new("ab\na\n.\n"));
+	editor.execute("0a").expect("cannot load text");
+	let res = editor.execute("g/a/s/x/X/").map(|_| ());
+	assert_eq!(res, Err(CommandError::NoMatch));
+}
+
+#[test]
+fn dry_run_previews_global_substitute() {
+	let mut out = Vec::new();
+	{
+		let mut editor = Editor::with_output(&mut out);
+		editor.set_silent(true);
+		editor.set_input(Cursor::new("ab\na\nab\n.\n"));
+		editor.execute("0a").expect("cannot load text");
+		editor.set_dry_run(true);
+		editor.execute("g/a/s/b/X/").expect("no preview");
+		editor.execute(",p").expect("cannot print buffer");
+	}
+	assert_eq!(
+		String::from_utf8_lossy(&out),
+		"-ab\n+aX\n-ab\n+aX\nab\na\nab\n"
+	);
+}
+
+#[test]
+fn mark_round_trip() {
+	assert_eq!(run("a\nb\nc\n", "", &["2kb", "1", "'b="]), "a\n2\n");
+}
+
+#[test]
+fn write_range() {
+	let path = temp_path("write-range");
+	run(
+		"1\n2\n3\n4\n5\n",
+		"",
+		&[&format!("2,3w {}", path.display())],
+	);
+	let text = fs::read_to_string(&path).expect("nothing written");
+	let _ = fs::remove_file(&path);
+	assert_eq!(text, "2\n3\n");
+}
+
+#[test]
+fn missing_final_newline() {
+	let path = temp_path("no-newline");
+	fs::write(&path, "a\nb").expect("cannot write fixture");
+	let mut out = Vec::new();
+	{
+		let mut editor = Editor::with_output(&mut out);
+		let f = path.to_str().expect("temp path is not UTF-8");
+		editor.open(f).expect("cannot open fixture");
+		editor.execute("w").expect("cannot write fixture");
+	}
+	let text = fs::read_to_string(&path).expect("nothing written");
+	let _ = fs::remove_file(&path);
+	assert_eq!(String::from_utf8_lossy(&out), "3\n3\n");
+	assert_eq!(text, "a\nb");
+}
+
+#[test]
+fn write_read_only_file() {
+	let dir = temp_path("read-only");
+	fs::create_dir_all(&dir).expect("cannot create directory");
+	let path = dir.join("ro.txt");
+	fs::write(&path, "a\n").expect("cannot write fixture");
+	let mut perms = fs::metadata(&path).expect("no fixture").permissions();
+	perms.set_readonly(true);
+	fs::set_permissions(&path, perms).expect("cannot make fixture read-only");
+	// Root writes to read-only files anyway
+	if fs::OpenOptions::new().write(true).open(&path).is_ok() {
+		let _ = fs::remove_dir_all(&dir);
+		return;
+	}
+	let res = {
+		let mut out = Vec::new();
+		let mut editor = Editor::with_output(&mut out);
+		editor.set_silent(true);
+		editor
+			.open(path.to_str().expect("temp path is not UTF-8"))
+			.expect("cannot open fixture");
+		editor.execute("s/a/b/").expect("cannot substitute");
+		editor.execute("w").map(|_| ())
+	};
+	let text = fs::read_to_string(&path).expect("fixture is gone");
+	let _ = fs::remove_dir_all(&dir);
+	assert_eq!(res, Err(CommandError::InvalidPath));
+	assert_eq!(text, "a\n");
+}
+
+#[test]
+fn write_quit_warns_about_ring() {
+	let dir = temp_path("ring");
+	fs::create_dir_all(&dir).expect("cannot create directory");
+	let (one, two) = (dir.join("one.txt"), dir.join("two.txt"));
+	fs::write(&one, "a\nb\n").expect("cannot write fixture");
+	fs::write(&two, "c\n").expect("cannot write fixture");
+	let mut out = Vec::new();
+	let (first, second) = {
+		let mut editor = Editor::with_output(&mut out);
+		editor.set_silent(true);
+		editor
+			.open(one.to_str().expect("temp path is not UTF-8"))
+			.expect("cannot open fixture");
+		editor.execute("1d").expect("cannot delete line");
+		editor
+			.execute(&format!("e {}", two.display()))
+			.expect("cannot edit other file");
+		let first = editor.execute("wq").map(|o| o.quit);
+		let second = editor.execute("wq").map(|o| o.quit);
+		(first, second)
+	};
+	let _ = fs::remove_dir_all(&dir);
+	assert_eq!(first, Err(CommandError::ModifiedBuffer));
+	assert_eq!(second, Ok(true));
+}
+
+#[test]
+fn substitute_backreferences() {
+	assert_eq!(run("foobar\n", "", &[r"s/(foo)(bar)/\2\1/p"]), "barfoo\n");
+	assert_eq!(run("foobar\n", "", &["s/o+/[&]/p"]), "f[oo]bar\n");
+	assert_eq!(run("foobar\n", "", &[r"s/o+/\&/p"]), "f&bar\n");
+	assert_eq!(run("foobar\n", "", &[r"s/o+/\\/p"]), "f\\bar\n");
+}
+
+#[test]
+fn empty_pattern_repeats_search() {
+	let text = "foo1\nbar\nfoo2\nbaz\nfoo3\n";
+	assert_eq!(run(text, "", &["1", "/foo/", "//"]), "foo1\nfoo2\nfoo3\n");
+}
+
+#[test]
+fn append_sets_current_line() {
+	assert_eq!(run("1\n2\n3\n", "x\ny\n.\n", &["2a", ".="]), "4\n");
+}
+
+#[test]
+fn read_sets_current_line() {
+	let path = temp_path("read");
+	fs::write(&path, "a\nb\nc\n").expect("cannot write fixture");
+	let out = run(
+		"1\n2\n3\n4\n",
+		"",
+		&[&format!("2r {}", path.display()), ".=", ",p"],
+	);
+	let _ = fs::remove_file(&path);
+	assert_eq!(out, "5\n1\n2\na\nb\nc\n3\n4\n");
+}
+
+#[test]
+fn print_empty_buffer() {
+	let mut out = Vec::new();
+	let res = Editor::with_output(&mut out).execute("p").map(|_| ());
+	assert_eq!(res, Err(CommandError::InvalidAddress));
+	assert!(out.is_empty());
+}
+
+const FIVE: &str = "1\n2\n3\n4\n5\n";
+
+#[test]
+fn change_to_one_line() {
+	assert_eq!(run(FIVE, "x\n.\n", &["2,4c", ".=", ",p"]), "2\n1\nx\n5\n");
+}
+
+#[test]
+fn change_to_no_lines() {
+	assert_eq!(run(FIVE, ".\n", &["2,4c", ".=", ",p"]), "1\n1\n5\n");
+}
+
+#[test]
+fn delete_last_line() {
+	assert_eq!(run(FIVE, "", &["$d", ".="]), "4\n");
+}
+
+#[test]
+fn delete_middle_range() {
+	assert_eq!(run(FIVE, "", &["2,3d", ".=", "p"]), "2\n4\n");
+}
+
+#[test]
+fn move_prints_current_line() {
+	assert_eq!(run(FIVE, "", &["1,2m4p", ".="]), "2\n4\n");
+}
+
+#[test]
+fn transfer_prints_current_line() {
+	assert_eq!(run(FIVE, "", &["1,2t3p", ".="]), "2\n5\n");
+}
+
+#[test]
+fn join_prints_current_line() {
+	assert_eq!(run(FIVE, "", &["1,2jp", "3,4jn"]), "12\n3\t45\n");
+}
+
+#[test]
+fn delete_prints_current_line() {
+	assert_eq!(run(FIVE, "", &["2dp", "$dn"]), "3\n3\t4\n");
+}
+
+#[test]
+fn delete_whole_buffer() {
+	assert_eq!(run(FIVE, "", &[",d", ".="]), "0\n");
+}
+
+#[test]
+fn append_prints_new_lines() {
+	assert_eq!(run(FIVE, "x\ny\n.\n", &["3ap"]), "x\ny\n");
+}
+
+// Input that is interrupted by Ctrl-C while waiting for a line
+struct Interrupting(Arc<AtomicBool>);
+
+impl Read for Interrupting {
+	fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+		self.fill_buf().map(|_| 0)
+	}
+}
+
+impl BufRead for Interrupting {
+	fn fill_buf(&mut self) -> io::Result<&[u8]> {
+		self.0.store(true, Ordering::Relaxed);
+		Err(io::ErrorKind::Interrupted.into())
+	}
+
+	fn consume(&mut self, _: usize) {}
+}
+
+#[test]
+fn interrupt_ends_input_mode() {
+	let flag = Arc::new(AtomicBool::new(false));
+	let mut out = Vec::new();
+	let mut editor = Editor::with_output(&mut out);
+	editor.set_interrupt(Arc::clone(&flag));
+	editor.set_input(Interrupting(flag));
+	let res = editor.execute("a").map(|_| ());
+	assert_eq!(res, Err(CommandError::Interrupted));
+}
+
+#[test]
+fn unknown_command_names_input() {
+	let mut out = Vec::new();
+	let mut editor = Editor::with_output(&mut out);
+	for (cmd, text) in [("foo", "foo"), ("1,5xyz", "xyz"), (" 2 x", "x")] {
+		let res = editor.execute(cmd).map(|_| ());
+		assert_eq!(res, Err(CommandError::UnknownCommand(String::from(text))));
+	}
+}
+
+#[test]
+fn search_finds_current_line_last() {
+	assert_eq!(run("a\nfoo\nb\n", "", &["2", "/foo/="]), "foo\n2\n");
+}
+
+#[test]
+fn search_without_match() {
+	let mut out = Vec::new();
+	let res = {
+		let mut editor = Editor::with_output(&mut out);
+		editor.set_input(Cursor::new("a\nb\n.\n"));
+		editor.execute("0a").expect("cannot load text");
+		let res = editor.execute("/foo/").map(|_| ());
+		editor.execute(".=").expect("cannot print line number");
+		res
+	};
+	assert_eq!(res, Err(CommandError::NoMatch));
+	assert_eq!(String::from_utf8_lossy(&out), "2\n");
+}
+
+#[test]
+fn delete_delimited_block() {
+	let text = "a\nBEGIN\nb\nEND\nc\n";
+	assert_eq!(run(text, "", &["1", "/BEGIN/,/END/d", ",p"]), "a\na\nc\n");
+}
+
+#[test]
+fn search_range_ends_after_start() {
+	let text = "x\nfoo\nx\nbar\nfoo\n";
+	assert_eq!(run(text, "", &["1", "/foo/;/x/n"]), "x\n2\tfoo\n3\tx\n");
+}
+
+#[test]
+fn backward_search_wraps() {
+	let text = "foo1\nbar\nfoo2\nfoo3\nbaz\n";
+	assert_eq!(run(text, "", &["1", "?foo?"]), "foo1\nfoo3\n");
+}
+
+#[test]
+fn file_prints_name() {
+	let dir = temp_path("file");
+	fs::create_dir_all(&dir).expect("cannot create directory");
+	let path = dir.join("foo.txt");
+	fs::write(&path, "a\n").expect("cannot write fixture");
+	let f = path.to_str().expect("temp path is not UTF-8");
+	let mut out = Vec::new();
+	{
+		let mut editor = Editor::with_output(&mut out);
+		editor.set_silent(true);
+		editor.open(f).expect("cannot open fixture");
+		editor.execute("f").expect("no filename");
+	}
+	let _ = fs::remove_dir_all(&dir);
+	assert_eq!(String::from_utf8_lossy(&out), format!("{}\n", f));
+}
+
+#[test]
+fn offset_from_last_line() {
+	assert_eq!(run(FIVE, "", &["$-1p", "$-1,$n"]), "4\n4\t4\n5\t5\n");
+}
+
+#[test]
+fn chained_offsets() {
+	assert_eq!(
+		run(FIVE, "", &["1", "++p", "--p", "+2-1+1p"]),
+		"1\n3\n1\n3\n"
+	);
+}